@@ -1,5 +1,9 @@
-use std::any::TypeId;
-use std::collections::{HashSet, HashMap, LinkedList};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::alloc::{self, Layout};
+use std::mem;
+use crate::bitset::ComponentSet;
+use crate::component::{ComponentId, ComponentRegistry, DropFn};
 use crate::entity::Entity;
 
 /// unique identifies an archetype
@@ -8,73 +12,490 @@ pub struct ArchetypeId {
     pub index: usize
 }
 
-/// uniquely identifies a set of components
+/// canonical identity of a set of components: component ids in sorted order,
+/// so the same set always yields the same signature regardless of insertion order
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Signature {
+    pub components: Vec<ComponentId>
+}
+
+impl Signature {
+    pub fn new(components: &[ComponentId]) -> Signature {
+        let mut components = components.to_vec();
+        components.sort();
+        components.dedup();
+        Signature { components }
+    }
+}
+
+/// uniquely identifies a set of components.
+/// component columns are laid out in signature order
 pub struct Archetype {
-    pub component_types: Vec<TypeId>,
-    pub component_sizes: Vec<usize>
+    pub components: Vec<ComponentId>,
+    /// same components as a bitset for query matching
+    pub component_set: ComponentSet,
+    /// archetype graph: cached transitions to the archetype with a component added or removed
+    pub(crate) add_edges: HashMap<ComponentId, ArchetypeId>,
+    pub(crate) remove_edges: HashMap<ComponentId, ArchetypeId>
+}
+
+impl Archetype {
+    pub fn new(components: &[ComponentId]) -> Archetype {
+        Archetype {
+            components: Signature::new(components).components,
+            component_set: ComponentSet::from_ids(components),
+            add_edges: HashMap::new(),
+            remove_edges: HashMap::new()
+        }
+    }
+
+    pub fn signature(&self) -> Signature {
+        return Signature { components: self.components.clone() };
+    }
+
+    /// index of a component within this archetype's layout
+    pub fn component_index(&self, component: ComponentId) -> Option<usize> {
+        return self.components.binary_search(&component).ok();
+    }
+
+    pub fn has_component(&self, component: ComponentId) -> bool {
+        return self.component_index(component).is_some();
+    }
 }
 
 /// default archetype for empty entities
 /// makes more sense than checking for archetype existence on every entity operation
 pub const DEFAULT_ARCHETYPE: ArchetypeId = ArchetypeId { index: 0 };
 
-/// linear component layout
+/// size in bytes of a single chunk of archetype storage
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// where an entity's components live: archetype, chunk within its storage and row within the chunk
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EntityLocation {
+    pub archetype: ArchetypeId,
+    pub chunk: usize,
+    pub row: usize
+}
+
+/// location of entities that have no storage row, i.e. dead or not yet placed entities.
+/// points at no archetype, so it can't be mistaken for a real row
+pub const INVALID_LOCATION: EntityLocation = EntityLocation {
+    archetype: ArchetypeId { index: usize::MAX },
+    chunk: usize::MAX,
+    row: usize::MAX
+};
+
+/// dense entity location table indexed by entity id.
+/// holds no versions, callers validate entity handles before looking them up
+#[derive(Default)]
+pub(crate) struct EntityLocations {
+    locations: Vec<EntityLocation>
+}
+
+impl EntityLocations {
+    pub(crate) fn get(&self, entity: Entity) -> EntityLocation {
+        return *self.locations.get(entity.id as usize).unwrap_or(&INVALID_LOCATION);
+    }
+
+    pub(crate) fn set(&mut self, entity: Entity, location: EntityLocation) {
+        let index = entity.id as usize;
+        if index >= self.locations.len() {
+            self.locations.resize(index + 1, INVALID_LOCATION);
+        }
+        self.locations[index] = location;
+    }
+}
+
+/// single zeroed allocation holding every column of a chunk, one after another.
+/// each column starts at its component's alignment so every row is a valid, aligned `T`
+struct Block {
+    ptr: *mut u8,
+    layout: Layout
+}
+
+impl Block {
+    /// allocate `capacity` rows of each component, returns the block along with its columns
+    fn create(capacity: usize, component_sizes: &[usize], component_aligns: &[usize]) -> (Block, Vec<Column>) {
+        let mut layout = Layout::from_size_align(0, 1).unwrap();
+        let mut offsets = Vec::with_capacity(component_sizes.len());
+        for (size, align) in component_sizes.iter().zip(component_aligns) {
+            let column = capacity.checked_mul(*size)
+                .and_then(|bytes| Layout::from_size_align(bytes, *align).ok())
+                .and_then(|column| layout.extend(column).ok());
+            match column {
+                Some((extended, offset)) => {
+                    layout = extended;
+                    offsets.push(offset);
+                }
+                None => panic!("ecs: chunk allocation failed: {} rows of {} bytes overflow", capacity, size)
+            }
+        }
+        let layout = layout.pad_to_align();
+        let ptr = if layout.size() == 0 {
+            // zero sized blocks never touch memory, any well aligned non-null pointer will do
+            layout.align() as *mut u8
+        } else {
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            if ptr.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ptr
+        };
+        let columns = offsets.iter().zip(component_sizes)
+            .map(|(offset, size)| Column { ptr: unsafe { ptr.add(*offset) }, size: *size }).collect();
+        return (Block { ptr, layout }, columns);
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            unsafe { alloc::dealloc(self.ptr, self.layout) };
+        }
+    }
+}
+
+/// contiguous component data for a single component type, a view into the block of its chunk
+pub(crate) struct Column {
+    ptr: *mut u8,
+    size: usize
+}
+
+impl Column {
+    /// pointer to the component at `row`
+    pub(crate) fn get_ptr(&self, row: usize) -> *mut u8 {
+        return unsafe { self.ptr.add(row * self.size) };
+    }
+
+    fn copy_row(&mut self, from: usize, to: usize) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.get_ptr(from), self.get_ptr(to), self.size);
+        }
+    }
+}
+
+/// fixed capacity block of rows. rows are kept dense, the first `entities.len()` rows are occupied.
+/// each component type has its own contiguous column so iterating a single component
+/// touches no other component data. all columns share a single allocation
+pub(crate) struct Chunk {
+    pub(crate) entities: Vec<Entity>,
+    pub(crate) columns: Vec<Column>,
+    /// memory behind `columns`
+    _block: Block,
+    /// per column, latest universe tick at which a component of the column was added to an entity
+    pub(crate) added_ticks: Vec<Cell<u64>>,
+    /// per column, latest universe tick at which a component of the column was written
+    pub(crate) changed_ticks: Vec<Cell<u64>>
+}
+
+impl Chunk {
+    fn create(capacity: usize, component_sizes: &[usize], component_aligns: &[usize]) -> Chunk {
+        let (block, columns) = Block::create(capacity, component_sizes, component_aligns);
+        Chunk {
+            entities: Vec::with_capacity(capacity),
+            columns,
+            _block: block,
+            added_ticks: vec![Cell::new(0); component_sizes.len()],
+            changed_ticks: vec![Cell::new(0); component_sizes.len()]
+        }
+    }
+
+    /// number of occupied rows
+    pub(crate) fn len(&self) -> usize {
+        return self.entities.len();
+    }
+
+    /// the spare chunk kept by a storage is the only empty one
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.entities.is_empty();
+    }
+
+    /// pointer to the component at `row` within `column`
+    pub(crate) fn component_ptr(&self, column: usize, row: usize) -> *mut u8 {
+        return self.columns[column].get_ptr(row);
+    }
+
+    /// forget the change ticks of an empty chunk before it is reused
+    fn reset_ticks(&self) {
+        for tick in self.added_ticks.iter().chain(&self.changed_ticks) {
+            tick.set(0);
+        }
+    }
+
+    /// record a write to `column`. ticks only move forward, so rows moved in from older chunks keep theirs
+    pub(crate) fn mark_changed(&self, column: usize, tick: u64) {
+        let changed = &self.changed_ticks[column];
+        changed.set(u64::max(changed.get(), tick));
+    }
+
+    /// record that components of `column` were added to entities, adding also counts as a write
+    pub(crate) fn mark_added(&self, column: usize, tick: u64) {
+        let added = &self.added_ticks[column];
+        added.set(u64::max(added.get(), tick));
+        self.mark_changed(column, tick);
+    }
+
+    /// view a column as a slice of its occupied rows
+    /// # Safety
+    /// `column` must hold components of type `T`
+    pub(crate) unsafe fn column<T>(&self, column: usize) -> &[T] {
+        let ptr = self.columns[column].ptr;
+        debug_assert_eq!(ptr as usize % mem::align_of::<T>(), 0, "ecs: misaligned component column");
+        return std::slice::from_raw_parts(ptr as *const T, self.len());
+    }
+
+    /// view a column as a mutable slice of its occupied rows
+    /// # Safety
+    /// `column` must hold components of type `T`
+    pub(crate) unsafe fn column_mut<T>(&mut self, column: usize) -> &mut [T] {
+        let ptr = self.columns[column].ptr;
+        debug_assert_eq!(ptr as usize % mem::align_of::<T>(), 0, "ecs: misaligned component column");
+        return std::slice::from_raw_parts_mut(ptr as *mut T, self.len());
+    }
+}
+
+/// column-oriented component layout split into chunks which are allocated on demand.
+/// chunks are released when empty, except for a single empty chunk kept as a spare
 pub(crate) struct ArchetypeStorage {
     pub(crate) archetype_id: ArchetypeId,
-    pub(crate) archetype_size: usize,
-    pub(crate) free_indices: LinkedList<usize>,
-    pub(crate) entity_indices: HashMap<Entity, usize>,
-    pub(crate) data: Vec<u8>
+    pub(crate) component_sizes: Vec<usize>,
+    pub(crate) component_aligns: Vec<usize>,
+    pub(crate) component_drops: Vec<Option<DropFn>>,
+    pub(crate) chunk_capacity: usize,
+    pub(crate) chunks: Vec<Chunk>,
+    /// indices of the chunks with free rows, so allocating a row never scans full chunks
+    pub(crate) free_chunks: Vec<usize>
 }
 
 impl ArchetypeStorage {
-    pub(crate) fn create(archetype_id: ArchetypeId, archetype_size: usize) -> ArchetypeStorage {
-        const MAX_ENTRIES: usize = 10000;
-        let data: Vec<u8> = Vec::with_capacity(MAX_ENTRIES * archetype_size);
-        let mut free_indices: LinkedList<usize> = LinkedList::new();
-        for i in 0..MAX_ENTRIES {
-            free_indices.push_back(i);
-        }
-
+    pub(crate) fn create(archetype_id: ArchetypeId, archetype: &Archetype,
+                         registry: &ComponentRegistry) -> ArchetypeStorage {
+        let infos: Vec<_> = archetype.components.iter().map(|c| registry.get_info(*c)).collect();
+        let component_sizes: Vec<usize> = infos.iter().map(|info| info.size).collect();
+        // each row also stores its entity handle, which keeps the capacity non-zero for empty rows
+        let row_size = component_sizes.iter().sum::<usize>() + mem::size_of::<Entity>();
         ArchetypeStorage {
             archetype_id,
-            archetype_size,
-            free_indices,
-            entity_indices: HashMap::with_capacity(10000),
-            data
+            component_sizes,
+            component_aligns: infos.iter().map(|info| info.align).collect(),
+            component_drops: infos.iter().map(|info| info.drop_fn).collect(),
+            chunk_capacity: usize::max(1, CHUNK_SIZE / row_size),
+            chunks: Vec::new(),
+            free_chunks: Vec::new()
+        }
+    }
+    /// chunk to allocate rows from: one with free rows if there is any, a new one otherwise
+    fn free_chunk(&mut self) -> usize {
+        if let Some(chunk) = self.free_chunks.last() {
+            return *chunk;
+        }
+        self.chunks.push(Chunk::create(self.chunk_capacity, &self.component_sizes, &self.component_aligns));
+        self.free_chunks.push(self.chunks.len() - 1);
+        return self.chunks.len() - 1;
+    }
+    /// allocate a row for the entity. the caller records the returned location
+    pub(crate) fn alloc_entity_index(&mut self, entity: Entity) -> EntityLocation {
+        let chunk = self.free_chunk();
+        let row = self.chunks[chunk].len();
+        self.chunks[chunk].entities.push(entity);
+        if self.chunks[chunk].len() == self.chunk_capacity {
+            self.free_chunks.pop();
+        }
+        return EntityLocation { archetype: self.archetype_id, chunk, row };
+    }
+    /// allocate consecutive rows for the entities, filling chunks with free space before creating new ones.
+    /// the caller records the returned locations
+    pub(crate) fn alloc_entity_indices(&mut self, entities: &[Entity]) -> Vec<EntityLocation> {
+        let mut locations = Vec::with_capacity(entities.len());
+        let mut remaining = entities;
+        while !remaining.is_empty() {
+            let chunk = self.free_chunk();
+            let count = usize::min(self.chunk_capacity - self.chunks[chunk].len(), remaining.len());
+            let first_row = self.chunks[chunk].len();
+            self.chunks[chunk].entities.extend_from_slice(&remaining[..count]);
+            for row in first_row..first_row + count {
+                locations.push(EntityLocation { archetype: self.archetype_id, chunk, row });
+            }
+            if self.chunks[chunk].len() == self.chunk_capacity {
+                self.free_chunks.pop();
+            }
+            remaining = &remaining[count..];
+        }
+        return locations;
+    }
+    /// allocate rows for the entities and move the staged values into them, column by column
+    pub(crate) fn alloc_staged(&mut self, entities: &[Entity], mut staged: StagedRows) -> Vec<EntityLocation> {
+        debug_assert!(staged.initialized.iter().all(|rows| *rows == entities.len()), "ecs: rows staged partially");
+        let locations = self.alloc_entity_indices(entities);
+        for (column, staged_column) in staged.columns.iter().enumerate() {
+            for (row, location) in locations.iter().enumerate() {
+                let dst = self.chunks[location.chunk].component_ptr(column, location.row);
+                unsafe { std::ptr::copy_nonoverlapping(staged_column.get_ptr(row), dst, staged_column.size) };
+            }
+        }
+        // the values belong to storage now
+        staged.initialized.clear();
+        return locations;
+    }
+
+    /// record that every component of the rows at `locations` was added at `tick`
+    pub(crate) fn mark_added(&self, locations: &[EntityLocation], tick: u64) {
+        let mut previous_chunk = None;
+        for location in locations {
+            if previous_chunk == Some(location.chunk) {
+                continue;
+            }
+            let chunk = &self.chunks[location.chunk];
+            for column in 0..chunk.columns.len() {
+                chunk.mark_added(column, tick);
+            }
+            previous_chunk = Some(location.chunk);
+        }
+    }
+
+    /// drop every component in the row at `location` and release it
+    pub(crate) fn drop_entity(&mut self, location: EntityLocation, locations: &mut EntityLocations) {
+        let chunk = &self.chunks[location.chunk];
+        for (column, drop_fn) in self.component_drops.iter().enumerate() {
+            if let Some(drop_fn) = drop_fn {
+                unsafe { drop_fn(chunk.component_ptr(column, location.row)) };
+            }
+        }
+        self.free_entity_index(location, locations);
+    }
+    /// release the row at `location`. the last row of the chunk is moved into the hole to keep rows
+    /// dense and the chunk itself is released once it holds no more rows, unless it is the only empty
+    /// chunk left. that one is kept as a spare so toggling a component on a lone entity doesn't
+    /// allocate and free a chunk every time. locations of entities moved in the process are updated,
+    /// the location of the released entity is left to the caller
+    pub(crate) fn free_entity_index(&mut self, location: EntityLocation, locations: &mut EntityLocations) {
+        let chunk = &mut self.chunks[location.chunk];
+        if chunk.len() == self.chunk_capacity {
+            self.free_chunks.push(location.chunk);
+        }
+        let last_row = chunk.len() - 1;
+        if location.row != last_row {
+            for column in chunk.columns.iter_mut() {
+                column.copy_row(last_row, location.row);
+            }
+        }
+        chunk.entities.swap_remove(location.row);
+        if location.row != last_row {
+            locations.set(chunk.entities[location.row], location);
+        }
+
+        if chunk.entities.is_empty() {
+            let empty = self.chunks.swap_remove(location.chunk);
+            // the last chunk took the released one's index
+            let moved_chunk = self.chunks.len();
+            self.free_chunks.retain(|chunk| *chunk != location.chunk);
+            for chunk in self.free_chunks.iter_mut().filter(|chunk| **chunk == moved_chunk) {
+                *chunk = location.chunk;
+            }
+            if location.chunk < self.chunks.len() {
+                for (row, moved) in self.chunks[location.chunk].entities.iter().enumerate() {
+                    locations.set(*moved, EntityLocation { archetype: self.archetype_id, chunk: location.chunk, row });
+                }
+            }
+            // empty chunks are always free ones, no need to look at full chunks
+            if !self.free_chunks.iter().any(|chunk| self.chunks[*chunk].is_empty()) {
+                empty.reset_ticks();
+                self.chunks.push(empty);
+                self.free_chunks.push(self.chunks.len() - 1);
+            }
         }
     }
-    pub(crate) fn alloc_entity_index(&mut self, entity: Entity) -> usize {
-        let index = self.free_indices.pop_front().unwrap();
-        self.entity_indices.insert(entity, index);
-        return index;
+}
+
+impl Drop for ArchetypeStorage {
+    fn drop(&mut self) {
+        // storage owns every value it holds, drop them all before the columns are released
+        for chunk in &self.chunks {
+            for (column, drop_fn) in self.component_drops.iter().enumerate() {
+                if let Some(drop_fn) = drop_fn {
+                    for row in 0..chunk.len() {
+                        unsafe { drop_fn(chunk.component_ptr(column, row)) };
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// rows of an archetype built outside of its storage, column by column. user `Default` and `Clone`
+/// impls run against the staged rows, so a panic in one of them never leaves partially initialized
+/// rows in storage. values built so far are dropped if the staging is abandoned
+pub(crate) struct StagedRows {
+    columns: Vec<Column>,
+    /// memory behind `columns`
+    _block: Block,
+    drops: Vec<Option<DropFn>>,
+    /// per column, number of leading rows holding a value
+    initialized: Vec<usize>
+}
+
+impl StagedRows {
+    pub(crate) fn create(storage: &ArchetypeStorage, rows: usize) -> StagedRows {
+        let (block, columns) = Block::create(rows, &storage.component_sizes, &storage.component_aligns);
+        return StagedRows {
+            columns,
+            _block: block,
+            drops: storage.component_drops.clone(),
+            initialized: vec![0; storage.component_sizes.len()]
+        };
+    }
+
+    /// initialize `rows` rows of `column`, `init` writes a value to the uninitialized memory behind the pointer
+    /// # Safety
+    /// `init` must write a valid value of the column's component type
+    pub(crate) unsafe fn fill(&mut self, column: usize, rows: usize, init: &mut dyn FnMut(*mut u8)) {
+        for row in 0..rows {
+            init(self.columns[column].get_ptr(row));
+            self.initialized[column] = row + 1;
+        }
+    }
+}
+
+impl Drop for StagedRows {
+    fn drop(&mut self) {
+        for (column, rows) in self.initialized.iter().enumerate() {
+            if let Some(drop_fn) = self.drops[column] {
+                for row in 0..*rows {
+                    unsafe { drop_fn(self.columns[column].get_ptr(row)) };
+                }
+            }
+        }
     }
 }
 
 pub struct ArchetypeManager {
-    pub(crate) entity_archetypes: HashMap<Entity, ArchetypeId>,
+    pub(crate) entity_locations: EntityLocations,
     pub(crate) archetypes: Vec<Archetype>,
+    pub(crate) archetype_ids: HashMap<Signature, ArchetypeId>,
     pub(crate) archetype_index_seq: usize,
 }
 
 impl Default for ArchetypeManager {
     fn default() -> Self {
+        let default_archetype = Archetype::new(&[]);
+        let mut archetype_ids = HashMap::new();
+        archetype_ids.insert(default_archetype.signature(), DEFAULT_ARCHETYPE);
         ArchetypeManager {
-            entity_archetypes: HashMap::new(),
-            archetypes: vec![Archetype {
-                component_types: vec![TypeId::of::<i32>()],
-                component_sizes: vec![std::mem::size_of::<i32>()] }],
+            entity_locations: EntityLocations::default(),
+            archetypes: vec![default_archetype],
+            archetype_ids,
             archetype_index_seq: 1 // begin at 1 after DEFAULT_ARCHETYPE
         }
     }
 }
 impl ArchetypeManager {
     pub fn get_archetype_id(&self, entity: Entity) -> ArchetypeId {
-        let arch = self.entity_archetypes.get(&entity);
-        if arch.is_none() {
-            return DEFAULT_ARCHETYPE;
-        }
-        return *arch.unwrap();
+        return self.entity_locations.get(entity).archetype;
+    }
+
+    pub fn get_entity_location(&self, entity: Entity) -> EntityLocation {
+        return self.entity_locations.get(entity);
     }
 
     pub fn get_archetype(&self, archetype_id: ArchetypeId) -> Option<&Archetype> {
@@ -86,16 +507,37 @@ impl ArchetypeManager {
         return self.get_archetype(archetype_id);
     }
 
-    pub fn find_archetype(&self, components: Vec<TypeId>) -> Option<&Archetype> {
-        return self.archetypes.iter().find(|arch| {
-            if arch.component_types.len() != components.len() {
-                return false;
-            }
-            return components.iter().all(|c| arch.component_types.contains(c));
-        });
+    /// find the archetype with exactly the given set of components, irrespective of order
+    pub fn find_archetype(&self, components: &[ComponentId]) -> Option<ArchetypeId> {
+        return self.find_archetype_by_signature(&Signature::new(components));
+    }
+
+    pub fn find_archetype_by_signature(&self, signature: &Signature) -> Option<ArchetypeId> {
+        return self.archetype_ids.get(signature).copied();
+    }
+
+    /// bumped whenever an archetype is created. archetypes are never removed,
+    /// so this is also the number of archetypes
+    pub fn generation(&self) -> usize {
+        return self.archetypes.len();
+    }
+
+    /// store a new archetype, returns its id. ids are never reused so they stay stable
+    pub(crate) fn register_archetype(&mut self, archetype: Archetype) -> ArchetypeId {
+        let archetype_id = ArchetypeId { index: self.archetype_index_seq };
+        self.archetype_ids.insert(archetype.signature(), archetype_id);
+        self.archetypes.push(archetype);
+        self.archetype_index_seq += 1;
+        return archetype_id;
+    }
+
+    /// cache the transition `from` + component = `to` and its inverse `to` - component = `from`
+    pub(crate) fn set_edge(&mut self, from: ArchetypeId, component: ComponentId, to: ArchetypeId) {
+        self.archetypes[from.index].add_edges.insert(component, to);
+        self.archetypes[to.index].remove_edges.insert(component, from);
     }
 
-    pub fn set_entity_archetype(&mut self, entity: Entity, archetype_id: ArchetypeId) {
-        self.entity_archetypes.insert(entity, archetype_id);
+    pub fn set_entity_location(&mut self, entity: Entity, location: EntityLocation) {
+        self.entity_locations.set(entity, location);
     }
-}
\ No newline at end of file
+}
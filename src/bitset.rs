@@ -0,0 +1,49 @@
+use crate::component::ComponentId;
+
+const WORD_BITS: usize = 64;
+
+/// set of component ids stored as a bitset, bit `n` set when component `n` is present
+#[derive(Clone, Debug, Default)]
+pub struct ComponentSet {
+    words: Vec<u64>
+}
+
+impl ComponentSet {
+    pub fn from_ids(ids: &[ComponentId]) -> ComponentSet {
+        let mut set = ComponentSet::default();
+        for id in ids {
+            set.insert(*id);
+        }
+        return set;
+    }
+
+    pub fn insert(&mut self, id: ComponentId) {
+        let word = id.index / WORD_BITS;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (id.index % WORD_BITS);
+    }
+
+    pub fn contains(&self, id: ComponentId) -> bool {
+        return self.word(id.index / WORD_BITS) & (1 << (id.index % WORD_BITS)) != 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.words.iter().all(|w| *w == 0);
+    }
+
+    /// every component of `other` is also in this set
+    pub fn is_superset(&self, other: &ComponentSet) -> bool {
+        return other.words.iter().enumerate().all(|(i, w)| self.word(i) & w == *w);
+    }
+
+    /// at least one component is in both sets
+    pub fn intersects(&self, other: &ComponentSet) -> bool {
+        return other.words.iter().enumerate().any(|(i, w)| self.word(i) & w != 0);
+    }
+
+    fn word(&self, index: usize) -> u64 {
+        return *self.words.get(index).unwrap_or(&0);
+    }
+}
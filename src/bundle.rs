@@ -0,0 +1,84 @@
+use std::mem::ManuallyDrop;
+use crate::component::{Component, ComponentId, ComponentRegistry};
+
+/// a group of components inserted with a single structural change.
+/// implemented for tuples of components, structs can implement it with `impl_bundle!`
+/// # Safety
+/// `component_ptrs` must hand out exactly one pointer per id returned by `component_ids`, in the
+/// same order, each pointing to a value of that id's component type. the pointers must stay valid
+/// until `component_ptrs` returns, storage copies the values out of them bitwise
+pub unsafe trait Bundle: Sized {
+    /// register every component of the bundle, ids in bundle order
+    fn component_ids(registry: &mut ComponentRegistry) -> Vec<ComponentId>;
+
+    /// hand a pointer to each component to `ptr`, in bundle order, without giving up ownership
+    fn component_ptrs(&self, ptr: &mut dyn FnMut(usize, *const u8));
+}
+
+/// hand a pointer to each component of the bundle to `put`, in bundle order, and give up ownership of them.
+/// `put` must move each value out (i.e. bitwise copy it) before returning
+pub(crate) fn put_bundle<B: Bundle>(bundle: B, put: &mut dyn FnMut(usize, *const u8)) {
+    // values are moved out through `put`, they must not be dropped here as well
+    let bundle = ManuallyDrop::new(bundle);
+    bundle.component_ptrs(put);
+}
+
+macro_rules! tuple_bundle {
+    ($($name:ident),*) => {
+        unsafe impl<$($name: Component + 'static),*> Bundle for ($($name,)*) {
+            fn component_ids(registry: &mut ComponentRegistry) -> Vec<ComponentId> {
+                return vec![$(registry.register::<$name>()),*];
+            }
+
+            #[allow(non_snake_case, unused_assignments)]
+            fn component_ptrs(&self, ptr: &mut dyn FnMut(usize, *const u8)) {
+                let ($($name,)*) = self;
+                let mut index = 0;
+                $(
+                    ptr(index, $name as *const $name as *const u8);
+                    index += 1;
+                )*
+            }
+        }
+    }
+}
+
+tuple_bundle!(A);
+tuple_bundle!(A, B);
+tuple_bundle!(A, B, C);
+tuple_bundle!(A, B, C, D);
+tuple_bundle!(A, B, C, D, E);
+tuple_bundle!(A, B, C, D, E, F);
+tuple_bundle!(A, B, C, D, E, F, G);
+tuple_bundle!(A, B, C, D, E, F, G, H);
+tuple_bundle!(A, B, C, D, E, F, G, H, I);
+tuple_bundle!(A, B, C, D, E, F, G, H, I, J);
+tuple_bundle!(A, B, C, D, E, F, G, H, I, J, K);
+tuple_bundle!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// implement `Bundle` for a struct whose fields are all components
+/// ```ignore
+/// struct Character { position: Position, velocity: Velocity }
+/// impl_bundle!(Character { position: Position, velocity: Velocity });
+/// ```
+#[macro_export]
+macro_rules! impl_bundle {
+    ($bundle:ident { $($field:ident : $component:ty),* $(,)? }) => {
+        unsafe impl $crate::bundle::Bundle for $bundle {
+            fn component_ids(registry: &mut $crate::component::ComponentRegistry)
+                -> Vec<$crate::component::ComponentId> {
+                return vec![$(registry.register::<$component>()),*];
+            }
+
+            #[allow(unused_assignments)]
+            fn component_ptrs(&self, ptr: &mut dyn FnMut(usize, *const u8)) {
+                let $bundle { $($field),* } = self;
+                let mut index = 0;
+                $(
+                    ptr(index, $field as *const $component as *const u8);
+                    index += 1;
+                )*
+            }
+        }
+    }
+}
@@ -1,8 +1,6 @@
 use std::collections::LinkedList;
 use crate::entity::Entity;
-use std::error::Error;
 use crate::universe::Universe;
-use crate::component::Component;
 
 /// ordered cmd buffer for batching entity operations (minimize chunk relayouts)
 pub struct CmdChain {
@@ -30,7 +28,7 @@ impl CmdChain {
     }
 
     pub fn destroy_entity(&mut self, entity: Entity) {
-        self.cmds.push_back(Box::new(CmdDestroyEntity { entity: entity }));
+        self.cmds.push_back(Box::new(CmdDestroyEntity { entity }));
     }
 }
 
@@ -43,9 +41,8 @@ pub struct CmdCreateEntity {
 }
 impl Cmd for CmdCreateEntity {
     fn exec(&self, universe: &mut Universe, state: &mut CmdChainState) {
-        let entity_id = universe.free_entity_indices.pop_front().unwrap();
-        let entity_version = universe.entity_versions.get(&entity_id).unwrap();
-        let entity = Entity { id: entity_id, version: *entity_version };
+        let entity = universe.entities.alloc();
+        universe.place_in_default_archetype(&[entity]);
         state.last_created_entity = Option::Some(entity);
     }
 }
@@ -55,10 +52,12 @@ pub struct CmdDestroyEntity {
     pub entity: Entity
 }
 impl Cmd for CmdDestroyEntity {
-    fn exec(&self, universe: &mut Universe, state: &mut CmdChainState) {
-        // increment version to invalidate previous entity handles
-        let version = universe.entity_versions.get_mut(&self.entity.id).unwrap();
-        *version += 1u64;
-        universe.free_entity_indices.push_front(self.entity.id);
+    fn exec(&self, universe: &mut Universe, _state: &mut CmdChainState) {
+        if !universe.is_valid(self.entity) {
+            panic!("ecs: destroy_entity failed: invalid entity {}", self.entity);
+        }
+        universe.release_entity_storage(self.entity);
+        // frees the id and increments its version to invalidate previous entity handles
+        universe.entities.free(self.entity);
     }
 }
\ No newline at end of file
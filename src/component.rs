@@ -1,4 +1,35 @@
-use std::any::TypeId;
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::{mem, ptr};
+
+/// type erased drop glue, drops the component value behind the pointer in place
+pub type DropFn = unsafe fn(*mut u8);
+
+/// drop glue for `T`, `None` when dropping `T` is a no-op
+pub fn drop_fn_of<T>() -> Option<DropFn> {
+    if mem::needs_drop::<T>() {
+        return Some(drop_component::<T>);
+    }
+    return None;
+}
+
+unsafe fn drop_component<T>(data: *mut u8) {
+    ptr::drop_in_place(data as *mut T);
+}
+
+/// type erased clone, writes a clone of the value behind `src` to uninitialized memory behind `dst`
+pub type CloneFn = unsafe fn(*const u8, *mut u8);
+
+unsafe fn clone_component<T: Clone>(src: *const u8, dst: *mut u8) {
+    ptr::write(dst as *mut T, (*(src as *const T)).clone());
+}
+
+/// type erased default constructor, writes a default value to uninitialized memory behind the pointer
+pub type DefaultFn = unsafe fn(*mut u8);
+
+unsafe fn default_component<T: Default>(data: *mut u8) {
+    ptr::write(data as *mut T, T::default());
+}
 
 /// component.
 /// trait `Sized` enforces fixed size
@@ -16,4 +47,109 @@ pub trait SharedComponent : Sized {
 /// trait `Sized` enforces fixed size
 pub trait SystemComponent : Sized {
 
-}
\ No newline at end of file
+}
+
+/// dense per universe component identifier, assigned in registration order
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ComponentId {
+    pub index: usize
+}
+
+/// everything storage needs to know about a component type without knowing the type
+#[derive(Clone, Debug)]
+pub struct ComponentInfo {
+    pub name: &'static str,
+    pub size: usize,
+    pub align: usize,
+    pub drop_fn: Option<DropFn>,
+    /// `None` for components that cannot be default initialized
+    pub default_fn: Option<DefaultFn>,
+    /// `None` for components that cannot be cloned
+    pub clone_fn: Option<CloneFn>,
+    pub type_id: TypeId
+}
+
+impl ComponentInfo {
+    pub fn of<T: 'static>() -> ComponentInfo {
+        ComponentInfo {
+            name: type_name::<T>(),
+            size: mem::size_of::<T>(),
+            align: mem::align_of::<T>(),
+            drop_fn: drop_fn_of::<T>(),
+            default_fn: None,
+            clone_fn: None,
+            type_id: TypeId::of::<T>()
+        }
+    }
+
+    pub fn of_default<T: Default + 'static>() -> ComponentInfo {
+        ComponentInfo {
+            default_fn: Some(default_component::<T>),
+            ..ComponentInfo::of::<T>()
+        }
+    }
+}
+
+/// maps component types to dense ids and their metadata
+#[derive(Default)]
+pub struct ComponentRegistry {
+    infos: Vec<ComponentInfo>,
+    ids: HashMap<TypeId, ComponentId>
+}
+
+impl ComponentRegistry {
+    /// id of component `T`, registering it on first use
+    pub fn register<T: Component + 'static>(&mut self) -> ComponentId {
+        if let Some(id) = self.get_id::<T>() {
+            return id;
+        }
+        return self.register_info(ComponentInfo::of::<T>());
+    }
+
+    /// id of component `T`, registering it on first use along with its default constructor
+    pub fn register_default<T: Component + Default + 'static>(&mut self) -> ComponentId {
+        if let Some(id) = self.get_id::<T>() {
+            self.infos[id.index].default_fn = ComponentInfo::of_default::<T>().default_fn;
+            return id;
+        }
+        return self.register_info(ComponentInfo::of_default::<T>());
+    }
+
+    /// id of component `T`, registering it on first use along with its clone function
+    pub fn register_clone<T: Component + Clone + 'static>(&mut self) -> ComponentId {
+        let id = self.register::<T>();
+        self.infos[id.index].clone_fn = Some(clone_component::<T>);
+        return id;
+    }
+
+    /// register type erased component metadata, returns the existing id if the type is already known
+    pub(crate) fn register_info(&mut self, info: ComponentInfo) -> ComponentId {
+        if let Some(id) = self.ids.get(&info.type_id) {
+            return *id;
+        }
+        let id = ComponentId { index: self.infos.len() };
+        self.ids.insert(info.type_id, id);
+        self.infos.push(info);
+        return id;
+    }
+
+    pub fn get_id<T: 'static>(&self) -> Option<ComponentId> {
+        return self.get_id_by_type(TypeId::of::<T>());
+    }
+
+    pub fn get_id_by_type(&self, type_id: TypeId) -> Option<ComponentId> {
+        return self.ids.get(&type_id).copied();
+    }
+
+    pub fn get_info(&self, id: ComponentId) -> &ComponentInfo {
+        return &self.infos[id.index];
+    }
+
+    pub fn len(&self) -> usize {
+        return self.infos.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.infos.is_empty();
+    }
+}
@@ -11,4 +11,73 @@ impl Display for Entity {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Entity {{ id: {}, version: {} }}", self.id, self.version)
     }
-}
\ No newline at end of file
+}
+/// per id allocation state. free slots are chained into a singly linked free list
+struct EntitySlot {
+    version: u64,
+    alive: bool,
+    next_free: Option<u64>
+}
+
+/// dense generational entity allocator, grows on demand.
+/// id 0 is reserved so a zeroed entity handle is never valid
+pub(crate) struct EntityAllocator {
+    slots: Vec<EntitySlot>,
+    free_head: Option<u64>
+}
+
+impl Default for EntityAllocator {
+    fn default() -> Self {
+        EntityAllocator {
+            slots: vec![EntitySlot { version: 0, alive: false, next_free: None }],
+            free_head: None
+        }
+    }
+}
+
+impl EntityAllocator {
+    /// allocate an entity, reusing the most recently freed id if there is one
+    pub(crate) fn alloc(&mut self) -> Entity {
+        if let Some(id) = self.free_head {
+            let slot = &mut self.slots[id as usize];
+            self.free_head = slot.next_free.take();
+            slot.alive = true;
+            return Entity { id, version: slot.version };
+        }
+        let id = self.slots.len() as u64;
+        self.slots.push(EntitySlot { version: 1, alive: true, next_free: None });
+        return Entity { id, version: 1 };
+    }
+
+    /// allocate `count` entities, reusing freed ids first and growing the slots once for the rest
+    pub(crate) fn alloc_many(&mut self, count: usize) -> Vec<Entity> {
+        let mut entities = Vec::with_capacity(count);
+        while entities.len() < count && self.free_head.is_some() {
+            entities.push(self.alloc());
+        }
+        let fresh = count - entities.len();
+        let first_id = self.slots.len() as u64;
+        self.slots.reserve(fresh);
+        for id in first_id..first_id + fresh as u64 {
+            self.slots.push(EntitySlot { version: 1, alive: true, next_free: None });
+            entities.push(Entity { id, version: 1 });
+        }
+        return entities;
+    }
+
+    /// free an entity, bumping the version of its id to invalidate outstanding handles
+    pub(crate) fn free(&mut self, entity: Entity) {
+        let slot = &mut self.slots[entity.id as usize];
+        slot.version += 1;
+        slot.alive = false;
+        slot.next_free = self.free_head;
+        self.free_head = Some(entity.id);
+    }
+
+    pub(crate) fn is_alive(&self, entity: Entity) -> bool {
+        return match self.slots.get(entity.id as usize) {
+            Some(slot) => slot.alive && slot.version == entity.version,
+            None => false
+        };
+    }
+}
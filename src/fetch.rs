@@ -0,0 +1,296 @@
+use std::any::{type_name, TypeId};
+use std::marker::PhantomData;
+use crate::component::Component;
+use crate::entity::Entity;
+use crate::query::{ChunkData, EntityQuery};
+
+/// components read and written by a typed query, used to reject aliasing borrows
+#[derive(Default)]
+pub struct Access {
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>
+}
+
+impl Access {
+    pub fn read<T: 'static>(&mut self) {
+        if self.writes.contains(&TypeId::of::<T>()) {
+            panic!("ecs: query failed: {} is borrowed mutably and immutably", type_name::<T>());
+        }
+        self.reads.push(TypeId::of::<T>());
+    }
+
+    pub fn write<T: 'static>(&mut self) {
+        if self.writes.contains(&TypeId::of::<T>()) {
+            panic!("ecs: query failed: {} is borrowed mutably more than once", type_name::<T>());
+        }
+        if self.reads.contains(&TypeId::of::<T>()) {
+            panic!("ecs: query failed: {} is borrowed mutably and immutably", type_name::<T>());
+        }
+        self.writes.push(TypeId::of::<T>());
+    }
+}
+
+/// one term of a typed query, e.g. `Entity`, `&T`, `&mut T`, `Option<&T>`, `With<T>`, `Without<T>`,
+/// `Changed<T>` or `Added<T>`, or a tuple of terms
+pub trait Fetch<'a> {
+    type Item;
+    /// per chunk cursor, e.g. a pointer to the start of a column
+    type State: Copy;
+
+    /// add the terms to the entity query used for archetype matching and record the borrows
+    fn add_terms(query: &mut EntityQuery, access: &mut Access);
+
+    /// whether a chunk of a matched archetype is visited at all, tested before `prepare`
+    fn filter(_chunk: &ChunkData<'a>, _last_run: u64) -> bool {
+        return true;
+    }
+
+    /// cursor for a chunk of a matched archetype
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State;
+
+    /// item for `row` of the chunk
+    /// # Safety
+    /// `row` must be an occupied row of the chunk `state` was prepared for, and each
+    /// row may be fetched at most once while the query lives
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item;
+}
+
+impl<'a> Fetch<'a> for Entity {
+    type Item = Entity;
+    type State = &'a [Entity];
+
+    fn add_terms(_query: &mut EntityQuery, _access: &mut Access) {}
+
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+        return chunk.entities();
+    }
+
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+        return *state.get_unchecked(row);
+    }
+}
+
+impl<'a, T: Component + 'static> Fetch<'a> for &'a T {
+    type Item = &'a T;
+    type State = *const T;
+
+    fn add_terms(query: &mut EntityQuery, access: &mut Access) {
+        access.read::<T>();
+        query.all.push(TypeId::of::<T>());
+    }
+
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+        return chunk.column_ptr::<T>().unwrap() as *const T;
+    }
+
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+        return &*state.add(row);
+    }
+}
+
+impl<'a, T: Component + 'static> Fetch<'a> for &'a mut T {
+    type Item = &'a mut T;
+    type State = *mut T;
+
+    fn add_terms(query: &mut EntityQuery, access: &mut Access) {
+        access.write::<T>();
+        query.all.push(TypeId::of::<T>());
+    }
+
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+        return chunk.column_ptr_mut::<T>().unwrap();
+    }
+
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+        return &mut *state.add(row);
+    }
+}
+
+/// `Some(&T)` for rows of archetypes containing `T`, `None` otherwise. does not constrain matching
+impl<'a, T: Component + 'static> Fetch<'a> for Option<&'a T> {
+    type Item = Option<&'a T>;
+    type State = Option<*const T>;
+
+    fn add_terms(_query: &mut EntityQuery, access: &mut Access) {
+        access.read::<T>();
+    }
+
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+        return chunk.column_ptr::<T>().map(|ptr| ptr as *const T);
+    }
+
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+        return state.map(|ptr| &*ptr.add(row));
+    }
+}
+
+/// `Some(&mut T)` for rows of archetypes containing `T`, `None` otherwise. does not constrain matching
+impl<'a, T: Component + 'static> Fetch<'a> for Option<&'a mut T> {
+    type Item = Option<&'a mut T>;
+    type State = Option<*mut T>;
+
+    fn add_terms(_query: &mut EntityQuery, access: &mut Access) {
+        access.write::<T>();
+    }
+
+    fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+        return chunk.column_ptr_mut::<T>();
+    }
+
+    unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+        return state.map(|ptr| &mut *ptr.add(row));
+    }
+}
+
+/// filter-only term, matches archetypes containing `T` without borrowing it
+pub struct With<T>(PhantomData<T>);
+
+impl<'a, T: Component + 'static> Fetch<'a> for With<T> {
+    type Item = ();
+    type State = ();
+
+    fn add_terms(query: &mut EntityQuery, _access: &mut Access) {
+        query.all.push(TypeId::of::<T>());
+    }
+
+    fn prepare(_chunk: &ChunkData<'a>) -> Self::State {}
+
+    unsafe fn fetch(_state: Self::State, _row: usize) -> Self::Item {}
+}
+
+/// filter-only term, matches archetypes without `T`
+pub struct Without<T>(PhantomData<T>);
+
+impl<'a, T: Component + 'static> Fetch<'a> for Without<T> {
+    type Item = ();
+    type State = ();
+
+    fn add_terms(query: &mut EntityQuery, _access: &mut Access) {
+        query.none.push(TypeId::of::<T>());
+    }
+
+    fn prepare(_chunk: &ChunkData<'a>) -> Self::State {}
+
+    unsafe fn fetch(_state: Self::State, _row: usize) -> Self::Item {}
+}
+
+/// filter-only term, matches chunks where `T` was written since the last run of the querying system.
+/// additions count as writes
+pub struct Changed<T>(PhantomData<T>);
+
+impl<'a, T: Component + 'static> Fetch<'a> for Changed<T> {
+    type Item = ();
+    type State = ();
+
+    fn add_terms(query: &mut EntityQuery, _access: &mut Access) {
+        query.all.push(TypeId::of::<T>());
+    }
+
+    fn filter(chunk: &ChunkData<'a>, last_run: u64) -> bool {
+        return chunk.changed_tick::<T>().unwrap() > last_run;
+    }
+
+    fn prepare(_chunk: &ChunkData<'a>) -> Self::State {}
+
+    unsafe fn fetch(_state: Self::State, _row: usize) -> Self::Item {}
+}
+
+/// filter-only term, matches chunks where `T` was added to an entity since the last run of the querying system
+pub struct Added<T>(PhantomData<T>);
+
+impl<'a, T: Component + 'static> Fetch<'a> for Added<T> {
+    type Item = ();
+    type State = ();
+
+    fn add_terms(query: &mut EntityQuery, _access: &mut Access) {
+        query.all.push(TypeId::of::<T>());
+    }
+
+    fn filter(chunk: &ChunkData<'a>, last_run: u64) -> bool {
+        return chunk.added_tick::<T>().unwrap() > last_run;
+    }
+
+    fn prepare(_chunk: &ChunkData<'a>) -> Self::State {}
+
+    unsafe fn fetch(_state: Self::State, _row: usize) -> Self::Item {}
+}
+
+macro_rules! tuple_fetch {
+    ($($name:ident),*) => {
+        impl<'a, $($name: Fetch<'a>),*> Fetch<'a> for ($($name,)*) {
+            type Item = ($($name::Item,)*);
+            type State = ($($name::State,)*);
+
+            fn add_terms(query: &mut EntityQuery, access: &mut Access) {
+                $($name::add_terms(query, access);)*
+            }
+
+            fn filter(chunk: &ChunkData<'a>, last_run: u64) -> bool {
+                return $($name::filter(chunk, last_run))&&*;
+            }
+
+            fn prepare(chunk: &ChunkData<'a>) -> Self::State {
+                return ($($name::prepare(chunk),)*);
+            }
+
+            #[allow(non_snake_case)]
+            unsafe fn fetch(state: Self::State, row: usize) -> Self::Item {
+                let ($($name,)*) = state;
+                return ($($name::fetch($name, row),)*);
+            }
+        }
+    }
+}
+
+tuple_fetch!(A);
+tuple_fetch!(A, B);
+tuple_fetch!(A, B, C);
+tuple_fetch!(A, B, C, D);
+tuple_fetch!(A, B, C, D, E);
+tuple_fetch!(A, B, C, D, E, F);
+tuple_fetch!(A, B, C, D, E, F, G);
+tuple_fetch!(A, B, C, D, E, F, G, H);
+tuple_fetch!(A, B, C, D, E, F, G, H, I);
+tuple_fetch!(A, B, C, D, E, F, G, H, I, J);
+tuple_fetch!(A, B, C, D, E, F, G, H, I, J, K);
+tuple_fetch!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// typed query over the universe, created with `Universe::query`.
+/// holds the universe borrow, so the fetched references stay valid while iterating
+pub struct Query<'a, Q: Fetch<'a>> {
+    chunks: std::vec::IntoIter<ChunkData<'a>>,
+    current: Option<(Q::State, usize)>,
+    row: usize,
+    /// tick `Changed<T>`/`Added<T>` terms compare against
+    last_run: u64,
+    marker: PhantomData<Q>
+}
+
+impl<'a, Q: Fetch<'a>> Query<'a, Q> {
+    pub(crate) fn new(chunks: Vec<ChunkData<'a>>, last_run: u64) -> Query<'a, Q> {
+        return Query { chunks: chunks.into_iter(), current: None, row: 0, last_run, marker: PhantomData };
+    }
+}
+
+impl<'a, Q: Fetch<'a>> Iterator for Query<'a, Q> {
+    type Item = Q::Item;
+
+    fn next(&mut self) -> Option<Q::Item> {
+        loop {
+            if let Some((state, len)) = self.current {
+                if self.row < len {
+                    let row = self.row;
+                    self.row += 1;
+                    return Some(unsafe { Q::fetch(state, row) });
+                }
+            }
+            let chunk = self.chunks.next()?;
+            if !Q::filter(&chunk, self.last_run) {
+                self.current = None;
+                continue;
+            }
+            self.current = Some((Q::prepare(&chunk), chunk.len()));
+            self.row = 0;
+        }
+    }
+}
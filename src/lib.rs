@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return, clippy::new_without_default, clippy::bool_assert_comparison)]
+
 pub mod component;
 pub mod universe;
 pub mod entity;
@@ -6,6 +8,10 @@ pub mod archetype;
 pub mod query;
 pub mod system;
 pub mod singleton;
+pub mod bitset;
+pub mod bundle;
+pub mod prefab;
+pub mod fetch;
 
 #[cfg(test)]
 mod test_full;
@@ -16,4 +22,6 @@ mod test_universe;
 #[cfg(test)]
 mod test_query;
 #[cfg(test)]
-mod test_systems;
\ No newline at end of file
+mod test_systems;
+#[cfg(test)]
+mod test_entity;
\ No newline at end of file
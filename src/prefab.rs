@@ -0,0 +1,7 @@
+use crate::component::Component;
+
+/// tag for template entities. queries skip entities with this tag unless they ask for it explicitly,
+/// `Universe::instantiate` creates copies without it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Prefab {}
+impl Component for Prefab {}
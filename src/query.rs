@@ -1,4 +1,10 @@
 use std::any::TypeId;
+use std::ops;
+use crate::bitset::ComponentSet;
+use crate::archetype::{Archetype, ArchetypeId, Chunk};
+use crate::component::{Component, ComponentRegistry};
+use crate::entity::Entity;
+use crate::prefab::Prefab;
 
 pub struct EntityQuery {
     pub all: Vec<TypeId>,
@@ -6,6 +12,296 @@ pub struct EntityQuery {
     pub any: Vec<TypeId>
 }
 
-pub struct EntityData {
-    pub num_entities: usize
+impl EntityQuery {
+    /// resolve component types to ids of `registry`. prefab exclusion is applied by `QueryExpr::to_mask`
+    fn resolve(&self, registry: &ComponentRegistry) -> QueryMask {
+        let mut mask = QueryMask {
+            all: ComponentSet::default(),
+            none: ComponentSet::default(),
+            any: ComponentSet::default(),
+            any_required: !self.any.is_empty(),
+            unsatisfiable: false
+        };
+        for type_id in &self.all {
+            match registry.get_id_by_type(*type_id) {
+                Some(id) => mask.all.insert(id),
+                // no archetype can contain a component that was never registered
+                None => mask.unsatisfiable = true
+            }
+        }
+        // unregistered components are trivially absent, they can be skipped for none/any
+        for type_id in &self.none {
+            if let Some(id) = registry.get_id_by_type(*type_id) {
+                mask.none.insert(id);
+            }
+        }
+        for type_id in &self.any {
+            if let Some(id) = registry.get_id_by_type(*type_id) {
+                mask.any.insert(id);
+            }
+        }
+        return mask;
+    }
+
+    fn mentions(&self, type_id: TypeId) -> bool {
+        return self.all.contains(&type_id) || self.none.contains(&type_id) || self.any.contains(&type_id);
+    }
+}
+
+/// boolean query expression over component presence, e.g.
+/// `QueryExpr::has::<Position>() & QueryExpr::any(vec![enemy, npc]) & !QueryExpr::has::<Dead>()`.
+/// leaves are flat `EntityQuery` terms, evaluated with bitwise ops
+pub enum QueryExpr {
+    Terms(EntityQuery),
+    And(Vec<QueryExpr>),
+    Or(Vec<QueryExpr>),
+    Not(Box<QueryExpr>)
+}
+
+impl QueryExpr {
+    /// matches archetypes containing `T`
+    pub fn has<T: 'static>() -> QueryExpr {
+        return QueryExpr::all(vec![TypeId::of::<T>()]);
+    }
+
+    /// matches archetypes containing every component of `types`
+    pub fn all(types: Vec<TypeId>) -> QueryExpr {
+        return QueryExpr::Terms(EntityQuery { all: types, none: vec![], any: vec![] });
+    }
+
+    /// matches archetypes containing at least one component of `types`
+    pub fn any(types: Vec<TypeId>) -> QueryExpr {
+        return QueryExpr::Terms(EntityQuery { all: vec![], none: vec![], any: types });
+    }
+
+    /// matches archetypes containing no component of `types`
+    pub fn none(types: Vec<TypeId>) -> QueryExpr {
+        return QueryExpr::Terms(EntityQuery { all: vec![], none: types, any: vec![] });
+    }
+
+    /// resolve component types to ids of `registry`.
+    /// prefabs are excluded unless `Prefab` appears somewhere in the expression
+    pub fn to_mask(&self, registry: &ComponentRegistry) -> ExprMask {
+        let mask = self.resolve(registry);
+        if self.mentions(TypeId::of::<Prefab>()) {
+            return mask;
+        }
+        return ExprMask::And(vec![mask, QueryExpr::none(vec![TypeId::of::<Prefab>()]).resolve(registry)]);
+    }
+
+    fn resolve(&self, registry: &ComponentRegistry) -> ExprMask {
+        return match self {
+            QueryExpr::Terms(query) => ExprMask::Terms(query.resolve(registry)),
+            QueryExpr::And(exprs) => ExprMask::And(exprs.iter().map(|expr| expr.resolve(registry)).collect()),
+            QueryExpr::Or(exprs) => ExprMask::Or(exprs.iter().map(|expr| expr.resolve(registry)).collect()),
+            QueryExpr::Not(expr) => ExprMask::Not(Box::new(expr.resolve(registry)))
+        };
+    }
+
+    fn mentions(&self, type_id: TypeId) -> bool {
+        return match self {
+            QueryExpr::Terms(query) => query.mentions(type_id),
+            QueryExpr::And(exprs) | QueryExpr::Or(exprs) => exprs.iter().any(|expr| expr.mentions(type_id)),
+            QueryExpr::Not(expr) => expr.mentions(type_id)
+        };
+    }
+}
+
+impl From<EntityQuery> for QueryExpr {
+    fn from(query: EntityQuery) -> QueryExpr {
+        return QueryExpr::Terms(query);
+    }
+}
+
+impl ops::BitAnd for QueryExpr {
+    type Output = QueryExpr;
+
+    fn bitand(self, rhs: QueryExpr) -> QueryExpr {
+        return match self {
+            // flatten chains like `a & b & c`
+            QueryExpr::And(mut exprs) => {
+                exprs.push(rhs);
+                QueryExpr::And(exprs)
+            }
+            lhs => QueryExpr::And(vec![lhs, rhs])
+        };
+    }
+}
+
+impl ops::BitOr for QueryExpr {
+    type Output = QueryExpr;
+
+    fn bitor(self, rhs: QueryExpr) -> QueryExpr {
+        return match self {
+            QueryExpr::Or(mut exprs) => {
+                exprs.push(rhs);
+                QueryExpr::Or(exprs)
+            }
+            lhs => QueryExpr::Or(vec![lhs, rhs])
+        };
+    }
+}
+
+impl ops::Not for QueryExpr {
+    type Output = QueryExpr;
+
+    fn not(self) -> QueryExpr {
+        return QueryExpr::Not(Box::new(self));
+    }
+}
+
+/// handle to a query registered with `Universe::register_query`.
+/// the matching archetypes are cached and only archetypes created since the last use are tested
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CachedQuery {
+    pub(crate) index: usize
+}
+
+pub(crate) struct CachedQueryState {
+    expr: QueryExpr,
+    mask: ExprMask,
+    /// number of registered components when `mask` was resolved
+    num_components: usize,
+    pub(crate) archetypes: Vec<ArchetypeId>,
+    /// archetype generation the cache was last updated at
+    pub(crate) generation: usize
+}
+
+impl CachedQueryState {
+    pub(crate) fn new(expr: QueryExpr, registry: &ComponentRegistry) -> CachedQueryState {
+        let mask = expr.to_mask(registry);
+        return CachedQueryState { expr, mask, num_components: registry.len(), archetypes: Vec::new(), generation: 0 };
+    }
+
+    /// test archetypes created since the last update, `archetypes` is indexed by archetype id
+    pub(crate) fn update(&mut self, archetypes: &[Archetype], registry: &ComponentRegistry) {
+        // components registered after the query was created only change the outcome for
+        // archetypes containing them, which are necessarily new, so only the mask is refreshed
+        if self.num_components != registry.len() {
+            self.mask = self.expr.to_mask(registry);
+            self.num_components = registry.len();
+        }
+        for (index, archetype) in archetypes.iter().enumerate().skip(self.generation) {
+            if self.mask.matches(&archetype.component_set) {
+                self.archetypes.push(ArchetypeId { index });
+            }
+        }
+        self.generation = archetypes.len();
+    }
+}
+
+/// query expression in bitset form
+pub enum ExprMask {
+    Terms(QueryMask),
+    And(Vec<ExprMask>),
+    Or(Vec<ExprMask>),
+    Not(Box<ExprMask>)
+}
+
+impl ExprMask {
+    pub fn matches(&self, components: &ComponentSet) -> bool {
+        return match self {
+            ExprMask::Terms(mask) => mask.matches(components),
+            ExprMask::And(masks) => masks.iter().all(|mask| mask.matches(components)),
+            ExprMask::Or(masks) => masks.iter().any(|mask| mask.matches(components)),
+            ExprMask::Not(mask) => !mask.matches(components)
+        };
+    }
+}
+
+/// entity query in bitset form, evaluated against archetype component sets with bitwise ops
+pub struct QueryMask {
+    pub all: ComponentSet,
+    pub none: ComponentSet,
+    pub any: ComponentSet,
+    any_required: bool,
+    unsatisfiable: bool
+}
+
+impl QueryMask {
+    pub fn matches(&self, components: &ComponentSet) -> bool {
+        if self.unsatisfiable {
+            return false;
+        }
+        if !components.is_superset(&self.all) || components.intersects(&self.none) {
+            return false;
+        }
+        return !self.any_required || components.intersects(&self.any);
+    }
+}
+
+/// result of `Universe::get_entities`, borrows the matched chunks from the universe
+pub struct EntityData<'a> {
+    pub num_entities: usize,
+    pub archetypes: Vec<ArchetypeId>,
+    pub chunks: Vec<ChunkData<'a>>
+}
+
+impl<'a> EntityData<'a> {
+    /// all matched entities, in chunk order
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        return self.chunks.iter().flat_map(|chunk| chunk.entities().iter().copied());
+    }
+}
+
+/// one matched chunk: a dense run of entities of a single archetype
+pub struct ChunkData<'a> {
+    pub archetype_id: ArchetypeId,
+    archetype: &'a Archetype,
+    registry: &'a ComponentRegistry,
+    chunk: &'a Chunk,
+    /// universe tick when the chunk was matched
+    tick: u64
+}
+
+impl<'a> ChunkData<'a> {
+    pub(crate) fn new(archetype_id: ArchetypeId, archetype: &'a Archetype, registry: &'a ComponentRegistry,
+                      chunk: &'a Chunk, tick: u64) -> ChunkData<'a> {
+        return ChunkData { archetype_id, archetype, registry, chunk, tick };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.chunk.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.chunk.len() == 0;
+    }
+
+    /// entities of the chunk, row `i` of every column belongs to entity `i`
+    pub fn entities(&self) -> &'a [Entity] {
+        return &self.chunk.entities;
+    }
+
+    /// column of component `T`, `None` if the archetype does not contain `T`
+    pub fn components<T: Component + 'static>(&self) -> Option<&'a [T]> {
+        let column = self.archetype.component_index(self.registry.get_id::<T>()?)?;
+        return Some(unsafe { self.chunk.column::<T>(column) });
+    }
+
+    /// latest tick at which `T` was added to an entity of the chunk, `None` if the archetype does not contain `T`
+    pub fn added_tick<T: Component + 'static>(&self) -> Option<u64> {
+        return self.column::<T>().map(|column| self.chunk.added_ticks[column].get());
+    }
+
+    /// latest tick at which `T` was written in the chunk, `None` if the archetype does not contain `T`
+    pub fn changed_tick<T: Component + 'static>(&self) -> Option<u64> {
+        return self.column::<T>().map(|column| self.chunk.changed_ticks[column].get());
+    }
+
+    /// start of the column of component `T`, `None` if the archetype does not contain `T`
+    pub(crate) fn column_ptr<T: Component + 'static>(&self) -> Option<*mut T> {
+        return self.column::<T>().map(|column| self.chunk.component_ptr(column, 0) as *mut T);
+    }
+
+    /// start of the column of component `T` for writing, stamps the column as changed
+    pub(crate) fn column_ptr_mut<T: Component + 'static>(&self) -> Option<*mut T> {
+        let column = self.column::<T>()?;
+        self.chunk.mark_changed(column, self.tick);
+        return Some(self.chunk.component_ptr(column, 0) as *mut T);
+    }
+
+    fn column<T: Component + 'static>(&self) -> Option<usize> {
+        return self.archetype.component_index(self.registry.get_id::<T>()?);
+    }
 }
@@ -1,4 +1,5 @@
 use crate::component::Component;
 
+#[allow(dead_code)]
 struct Singleton {}
 impl Component for Singleton {}
\ No newline at end of file
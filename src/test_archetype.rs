@@ -1,9 +1,232 @@
 // archetype tests
 
-use crate::archetype::ArchetypeId;
+use crate::archetype::{Archetype, ArchetypeId, ArchetypeManager, ArchetypeStorage, EntityLocation, EntityLocations,
+                       Signature, CHUNK_SIZE, DEFAULT_ARCHETYPE, INVALID_LOCATION};
+use crate::component::{ComponentId, ComponentInfo, ComponentRegistry};
+use crate::entity::Entity;
+
+/// storage for an archetype made of the given components, columns in the order given
+fn storage_of(infos: Vec<ComponentInfo>) -> ArchetypeStorage {
+    let mut registry = ComponentRegistry::default();
+    let components: Vec<ComponentId> = infos.into_iter().map(|info| registry.register_info(info)).collect();
+    return ArchetypeStorage::create(ArchetypeId { index: 1 }, &Archetype::new(&components), &registry);
+}
 
 #[test]
 fn test_archetype_id_equality() {
     assert_eq!(ArchetypeId { index: 1 }, ArchetypeId { index: 1 });
     assert_ne!(ArchetypeId { index: 1 }, ArchetypeId { index: 2 });
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_storage_chunk_capacity_from_row_size() {
+    let storage = storage_of(vec![ComponentInfo::of::<u64>(), ComponentInfo::of::<f64>()]);
+    assert_eq!(storage.chunk_capacity, CHUNK_SIZE / (16 + std::mem::size_of::<Entity>()));
+    let storage = storage_of(vec![ComponentInfo::of::<[u8; CHUNK_SIZE * 2]>()]);
+    assert_eq!(storage.chunk_capacity, 1);
+}
+
+#[test]
+fn test_storage_grows_and_releases_chunks() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<[u8; 64]>()]);
+    let mut locations = EntityLocations::default();
+    let count = storage.chunk_capacity * 3 + 1;
+    let entities: Vec<Entity> = (1..=count as u64).map(|id| Entity { id, version: 1 }).collect();
+    for entity in &entities {
+        let location = storage.alloc_entity_index(*entity);
+        locations.set(*entity, location);
+    }
+    assert_eq!(storage.chunks.len(), 4);
+
+    for entity in &entities {
+        storage.free_entity_index(locations.get(*entity), &mut locations);
+        locations.set(*entity, INVALID_LOCATION);
+    }
+    // one empty chunk is kept as a spare
+    assert_eq!(storage.chunks.len(), 1);
+    assert!(storage.chunks[0].is_empty());
+}
+
+#[test]
+fn test_storage_free_keeps_rows_dense() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<u8>(), ComponentInfo::of::<u32>()]);
+    let mut locations = EntityLocations::default();
+    let a = Entity { id: 1, version: 1 };
+    let b = Entity { id: 2, version: 1 };
+    let c = Entity { id: 3, version: 1 };
+    for (i, entity) in [a, b, c].iter().enumerate() {
+        let location = storage.alloc_entity_index(*entity);
+        locations.set(*entity, location);
+        unsafe {
+            *storage.chunks[location.chunk].component_ptr(0, location.row) = i as u8;
+            *(storage.chunks[location.chunk].component_ptr(1, location.row) as *mut u32) = i as u32;
+        }
+    }
+
+    storage.free_entity_index(locations.get(a), &mut locations);
+    // c moves into the hole left by a, taking its data along
+    let archetype = ArchetypeId { index: 1 };
+    assert_eq!(locations.get(c), EntityLocation { archetype, chunk: 0, row: 0 });
+    unsafe {
+        assert_eq!(storage.chunks[0].column::<u8>(0), &[2, 1]);
+        assert_eq!(storage.chunks[0].column::<u32>(1), &[2, 1]);
+    }
+    assert_eq!(locations.get(b), EntityLocation { archetype, chunk: 0, row: 1 });
+    assert_eq!(storage.chunks[0].entities, vec![c, b]);
+}
+
+#[test]
+fn test_storage_release_chunk_relocates_last_chunk() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<[u8; CHUNK_SIZE]>()]);
+    let mut locations = EntityLocations::default();
+    let a = Entity { id: 1, version: 1 };
+    let b = Entity { id: 2, version: 1 };
+    for entity in [a, b].iter() {
+        let location = storage.alloc_entity_index(*entity);
+        locations.set(*entity, location);
+    }
+    assert_eq!(storage.chunks.len(), 2);
+
+    storage.free_entity_index(locations.get(a), &mut locations);
+    assert_eq!(locations.get(b), EntityLocation { archetype: ArchetypeId { index: 1 }, chunk: 0, row: 0 });
+    // the emptied chunk is kept as the spare, behind the occupied ones
+    assert_eq!(storage.chunks.len(), 2);
+    assert!(storage.chunks[1].is_empty());
+
+    // the spare is reused, and emptied chunks beyond it are released
+    let c = Entity { id: 3, version: 1 };
+    for entity in [a, c].iter() {
+        let location = storage.alloc_entity_index(*entity);
+        locations.set(*entity, location);
+    }
+    assert_eq!(locations.get(a).chunk, 1);
+    assert_eq!(storage.chunks.len(), 3);
+    for entity in [a, c].iter() {
+        storage.free_entity_index(locations.get(*entity), &mut locations);
+    }
+    assert_eq!(storage.chunks.len(), 2);
+    assert_eq!(locations.get(b).chunk, 0);
+}
+
+#[test]
+fn test_storage_tracks_chunks_with_free_rows() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<[u8; 64]>()]);
+    let mut locations = EntityLocations::default();
+    let count = storage.chunk_capacity * 3;
+    let entities: Vec<Entity> = (1..=count as u64).map(|id| Entity { id, version: 1 }).collect();
+    for (entity, location) in entities.iter().zip(storage.alloc_entity_indices(&entities)) {
+        locations.set(*entity, location);
+    }
+    assert!(storage.free_chunks.is_empty());
+
+    // a freed row in the first chunk is reused instead of growing the storage
+    storage.free_entity_index(locations.get(entities[0]), &mut locations);
+    assert_eq!(storage.free_chunks, vec![0]);
+    let location = storage.alloc_entity_index(entities[0]);
+    locations.set(entities[0], location);
+    assert_eq!(location.chunk, 0);
+    assert_eq!(storage.chunks.len(), 3);
+
+    // emptying a chunk moves the last chunk into its index, the index follows
+    for entity in &entities[..storage.chunk_capacity] {
+        storage.free_entity_index(locations.get(*entity), &mut locations);
+    }
+    storage.free_entity_index(locations.get(entities[count - 1]), &mut locations);
+    let mut free_chunks = storage.free_chunks.clone();
+    free_chunks.sort();
+    let expected: Vec<usize> = (0..storage.chunks.len())
+        .filter(|chunk| storage.chunks[*chunk].len() < storage.chunk_capacity).collect();
+    assert_eq!(free_chunks, expected);
+    assert_eq!(storage.chunks.len(), 3);
+}
+
+#[test]
+fn test_storage_columns_are_aligned() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<u8>(), ComponentInfo::of::<f64>(),
+                                      ComponentInfo::of::<u128>()]);
+    for id in 1..100 {
+        let location = storage.alloc_entity_index(Entity { id, version: 1 });
+        let chunk = &storage.chunks[location.chunk];
+        assert_eq!(chunk.component_ptr(1, location.row) as usize % 8, 0);
+        assert_eq!(chunk.component_ptr(2, location.row) as usize % std::mem::align_of::<u128>(), 0);
+    }
+}
+
+#[test]
+fn test_location_table_defaults_to_invalid_location() {
+    let mut locations = EntityLocations::default();
+    let entity = Entity { id: 42, version: 1 };
+    assert_eq!(locations.get(entity), INVALID_LOCATION);
+    let location = EntityLocation { archetype: ArchetypeId { index: 3 }, chunk: 1, row: 7 };
+    locations.set(entity, location);
+    assert_eq!(locations.get(entity), location);
+    assert_eq!(locations.get(Entity { id: 41, version: 1 }), INVALID_LOCATION);
+}
+
+#[test]
+fn test_signature_is_order_independent() {
+    let a = ComponentId { index: 0 };
+    let b = ComponentId { index: 1 };
+    assert_eq!(Signature::new(&[a, b]), Signature::new(&[b, a]));
+    assert_ne!(Signature::new(&[a]), Signature::new(&[a, b]));
+
+    let archetype1 = Archetype::new(&[a, b]);
+    let archetype2 = Archetype::new(&[b, a]);
+    assert_eq!(archetype1.signature(), archetype2.signature());
+    assert_eq!(archetype1.components, archetype2.components);
+    assert_eq!(archetype1.component_index(b), Some(1));
+}
+
+#[test]
+fn test_chunk_columns_share_one_allocation() {
+    let mut storage = storage_of(vec![ComponentInfo::of::<u8>(), ComponentInfo::of::<f64>(),
+                                      ComponentInfo::of::<u32>()]);
+    storage.alloc_entity_index(Entity { id: 1, version: 1 });
+    let capacity = storage.chunk_capacity;
+    let chunk = &storage.chunks[0];
+    // columns follow each other, each padded to the alignment of the next
+    let start = chunk.component_ptr(0, 0) as usize;
+    let second = start + capacity.next_multiple_of(8);
+    assert_eq!(chunk.component_ptr(1, 0) as usize, second);
+    assert_eq!(chunk.component_ptr(2, 0) as usize, second + capacity * 8);
+}
+
+#[test]
+fn test_manager_finds_archetype_by_signature() {
+    let a = ComponentId { index: 0 };
+    let b = ComponentId { index: 1 };
+    let mut manager = ArchetypeManager::default();
+    assert_eq!(manager.find_archetype(&[]), Some(DEFAULT_ARCHETYPE));
+    assert_eq!(manager.find_archetype(&[a, b]), None);
+
+    let archetype_id = manager.register_archetype(Archetype::new(&[b, a]));
+    assert_eq!(manager.find_archetype(&[a, b]), Some(archetype_id));
+    assert_eq!(manager.find_archetype(&[b, a]), Some(archetype_id));
+    assert_eq!(manager.find_archetype_by_signature(&Signature::new(&[a, b])), Some(archetype_id));
+    assert_eq!(manager.find_archetype(&[a]), None);
+}
+
+#[test]
+fn test_registry_assigns_dense_ids() {
+    struct A {}
+    impl crate::component::Component for A {}
+    struct B { _value: String }
+    impl crate::component::Component for B {}
+
+    let mut registry = ComponentRegistry::default();
+    let a = registry.register::<A>();
+    let b = registry.register::<B>();
+    assert_eq!(a, ComponentId { index: 0 });
+    assert_eq!(b, ComponentId { index: 1 });
+    assert_eq!(registry.register::<A>(), a);
+    assert_eq!(registry.get_id::<B>(), Some(b));
+    assert_eq!(registry.get_id::<u8>(), None);
+    assert_eq!(registry.len(), 2);
+
+    let info = registry.get_info(b);
+    assert!(info.name.ends_with("B"));
+    assert_eq!(info.size, std::mem::size_of::<String>());
+    assert_eq!(info.align, std::mem::align_of::<String>());
+    assert!(info.drop_fn.is_some());
+    assert!(registry.get_info(a).drop_fn.is_none());
+}
@@ -0,0 +1,49 @@
+use crate::entity::{Entity, EntityAllocator};
+use crate::universe::Universe;
+
+#[test]
+fn allocator_grows_on_demand() {
+    let mut u = Universe::new();
+    let entities: Vec<Entity> = (0..50000).map(|_| u.create_entity()).collect();
+    assert_eq!(entities.last().unwrap().id, 50000);
+    assert!(entities.iter().all(|e| u.is_valid(*e)));
+}
+
+#[test]
+fn allocator_reuses_most_recently_freed_id() {
+    let mut allocator = EntityAllocator::default();
+    let a = allocator.alloc();
+    let b = allocator.alloc();
+    allocator.free(a);
+    allocator.free(b);
+    assert_eq!(allocator.alloc(), Entity { id: b.id, version: 2 });
+    assert_eq!(allocator.alloc(), Entity { id: a.id, version: 2 });
+    assert_eq!(allocator.alloc(), Entity { id: 3, version: 1 });
+}
+
+#[test]
+fn allocator_rejects_stale_and_unallocated_handles() {
+    let mut allocator = EntityAllocator::default();
+    let entity = allocator.alloc();
+    assert!(allocator.is_alive(entity));
+    assert!(!allocator.is_alive(Entity { id: 0, version: 0 }));
+    assert!(!allocator.is_alive(Entity { id: 2, version: 1 }));
+
+    allocator.free(entity);
+    assert!(!allocator.is_alive(entity));
+    // the next version is not valid until the id is handed out again
+    assert!(!allocator.is_alive(Entity { id: entity.id, version: entity.version + 1 }));
+}
+
+#[test]
+fn create_destroy_churn() {
+    let mut u = Universe::new();
+    for _ in 0..100 {
+        let entities: Vec<Entity> = (0..1000).map(|_| u.create_entity()).collect();
+        for entity in entities {
+            u.destroy_entity(entity);
+        }
+    }
+    // ids are recycled rather than growing the allocator
+    assert!(u.create_entity().id <= 1000);
+}
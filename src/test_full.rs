@@ -3,6 +3,7 @@
 use crate::universe::Universe;
 use crate::component::Component;
 
+#[derive(Clone)]
 struct Position {
     value: f32
 }
@@ -14,5 +15,6 @@ fn test_add_get_component() {
     let entity = uni.create_entity();
     let position = Position { value: 1337f32 };
     uni.add_component_data(entity, position);
-    //let position2 = uni.get_component()
+    let position2 = uni.get_component::<Position>(entity);
+    assert_eq!(position2.value, 1337f32);
 }
\ No newline at end of file
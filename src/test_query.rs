@@ -1,16 +1,26 @@
 use crate::query::EntityQuery;
 use std::any::TypeId;
-use crate::component::Component;
+use crate::bitset::ComponentSet;
+use crate::component::{Component, ComponentId};
+use crate::entity::Entity;
 use crate::universe::Universe;
 
+#[derive(Clone)]
+struct Position { pos: i32 }
+impl Component for Position {}
+struct Enemy {}
+impl Component for Enemy {}
+struct Npc {}
+impl Component for Npc {}
+struct Dead {}
+impl Component for Dead {}
+
 #[test]
 fn test_query() {
-    struct Position { pos: i32 }
-    impl Component for Position {}
-
     let mut u = Universe::new();
     let entity = u.create_entity();
     u.add_component_data(entity, Position { pos: 1337 });
+    assert_eq!(1337, u.get_component::<Position>(entity).pos);
 
     let query = EntityQuery {
         all: vec![TypeId::of::<Position>()],
@@ -18,4 +28,356 @@ fn test_query() {
         any: vec![]
     };
     let data = u.get_entities(query);
-}
\ No newline at end of file
+    assert_eq!(1, data.num_entities);
+}
+
+#[test]
+fn test_component_set() {
+    let a = ComponentId { index: 1 };
+    let b = ComponentId { index: 70 };
+    let c = ComponentId { index: 130 };
+    let ab = ComponentSet::from_ids(&[a, b]);
+    assert!(ab.contains(a) && ab.contains(b) && !ab.contains(c));
+    assert!(ab.is_superset(&ComponentSet::from_ids(&[b])));
+    assert!(!ab.is_superset(&ComponentSet::from_ids(&[b, c])));
+    assert!(ab.is_superset(&ComponentSet::default()));
+    assert!(ab.intersects(&ComponentSet::from_ids(&[b, c])));
+    assert!(!ab.intersects(&ComponentSet::from_ids(&[c])));
+    assert!(ComponentSet::default().is_empty());
+}
+
+#[test]
+fn test_query_none_and_any() {
+    let mut u = Universe::new();
+    let spawn = |u: &mut Universe, enemy: bool, npc: bool, dead: bool| {
+        let entity = u.create_entity();
+        u.add_component_data(entity, Position { pos: 0 });
+        if enemy { u.add_component_data(entity, Enemy {}); }
+        if npc { u.add_component_data(entity, Npc {}); }
+        if dead { u.add_component_data(entity, Dead {}); }
+    };
+    spawn(&mut u, true, false, false);
+    spawn(&mut u, true, false, true);
+    spawn(&mut u, false, true, false);
+    spawn(&mut u, false, false, false);
+
+    let data = u.get_entities(EntityQuery {
+        all: vec![TypeId::of::<Position>()],
+        none: vec![TypeId::of::<Dead>()],
+        any: vec![TypeId::of::<Enemy>(), TypeId::of::<Npc>()]
+    });
+    assert_eq!(2, data.num_entities);
+
+    let data = u.get_entities(EntityQuery {
+        all: vec![],
+        none: vec![TypeId::of::<Enemy>()],
+        any: vec![]
+    });
+    assert_eq!(2, data.num_entities);
+}
+
+#[test]
+fn test_query_unregistered_components() {
+    struct Unused {}
+    impl Component for Unused {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, Position { pos: 0 });
+
+    let query = |all: Vec<TypeId>, none: Vec<TypeId>, any: Vec<TypeId>| EntityQuery { all, none, any };
+    assert_eq!(0, u.get_entities(query(vec![TypeId::of::<Unused>()], vec![], vec![])).num_entities);
+    assert_eq!(1, u.get_entities(query(vec![TypeId::of::<Position>()], vec![TypeId::of::<Unused>()], vec![])).num_entities);
+    assert_eq!(0, u.get_entities(query(vec![], vec![], vec![TypeId::of::<Unused>()])).num_entities);
+}
+
+#[test]
+fn test_query_skips_prefabs() {
+    use crate::prefab::Prefab;
+
+    let mut u = Universe::new();
+    u.register_clone_component::<Position>();
+    let prefab = u.create_entity_with((Prefab {}, Position { pos: 1 }));
+    u.instantiate(prefab, 3);
+
+    let data = u.get_entities(EntityQuery { all: vec![TypeId::of::<Position>()], none: vec![], any: vec![] });
+    assert_eq!(3, data.num_entities);
+
+    let data = u.get_entities(EntityQuery { all: vec![TypeId::of::<Position>(), TypeId::of::<Prefab>()], none: vec![], any: vec![] });
+    assert_eq!(1, data.num_entities);
+}
+
+#[test]
+fn test_query_results() {
+    let mut u = Universe::new();
+    let mut expected = Vec::new();
+    for pos in 0..2000 {
+        let entity = u.create_entity_with((Position { pos },));
+        if pos % 2 == 0 {
+            u.add_component_data(entity, Enemy {});
+        }
+        expected.push(entity);
+    }
+    u.create_entity_with((Npc {},));
+
+    let data = u.get_entities(EntityQuery { all: vec![TypeId::of::<Position>()], none: vec![], any: vec![] });
+    assert_eq!(2000, data.num_entities);
+    assert_eq!(2, data.archetypes.len());
+    assert!(data.chunks.len() > 2);
+
+    let mut entities: Vec<Entity> = data.entities().collect();
+    entities.sort_by_key(|entity| entity.id);
+    assert_eq!(expected, entities);
+
+    for chunk in &data.chunks {
+        let positions = chunk.components::<Position>().unwrap();
+        assert_eq!(chunk.len(), positions.len());
+        assert_eq!(chunk.len(), chunk.entities().len());
+        for (entity, position) in chunk.entities().iter().zip(positions) {
+            assert_eq!(u.get_component_ref::<Position>(*entity).unwrap().pos, position.pos);
+        }
+        assert!(chunk.components::<Npc>().is_none());
+    }
+
+    let data = u.get_entities(EntityQuery { all: vec![TypeId::of::<Position>()], none: vec![TypeId::of::<Enemy>()], any: vec![] });
+    assert_eq!(1000, data.num_entities);
+    assert!(data.entities().all(|entity| u.get_component_ref::<Position>(entity).unwrap().pos % 2 == 1));
+}
+
+#[test]
+fn test_typed_query() {
+    struct Velocity { v: i32 }
+    impl Component for Velocity {}
+
+    let mut u = Universe::new();
+    for pos in 0..1500 {
+        let entity = u.create_entity_with((Position { pos }, Velocity { v: 2 }));
+        if pos % 3 == 0 {
+            u.add_component_data(entity, Enemy {});
+        }
+    }
+    u.create_entity_with((Position { pos: -1 },));
+
+    u.query::<(&Velocity, &mut Position)>().for_each(|(velocity, position)| position.pos += velocity.v);
+    let mut count = 0;
+    u.query::<(Entity, &Position, &Velocity)>().for_each(|(entity, position, _)| {
+        assert!(entity.id > 0);
+        assert!(position.pos >= 2);
+        count += 1;
+    });
+    assert_eq!(1500, count);
+
+    let enemies: Vec<(Entity, &Position)> = u.query::<(Entity, &Position, &Enemy)>()
+        .map(|(entity, position, _)| (entity, position)).collect();
+    assert_eq!(500, enemies.len());
+    assert!(enemies.iter().all(|(_, position)| (position.pos - 2) % 3 == 0));
+
+    // single terms are queried as one element tuples
+    assert_eq!(1501, u.query::<(&Position,)>().count());
+    assert_eq!(0, u.query::<(&Dead,)>().count());
+}
+
+#[test]
+#[should_panic(expected = "borrowed mutably more than once")]
+fn test_typed_query_rejects_aliasing() {
+    let mut u = Universe::new();
+    u.query::<(&mut Position, &mut Position)>();
+}
+
+#[test]
+#[should_panic(expected = "borrowed mutably and immutably")]
+fn test_typed_query_rejects_shared_and_mutable() {
+    let mut u = Universe::new();
+    u.query::<(&Position, &mut Position)>();
+}
+
+#[test]
+fn test_typed_query_optional_and_filters() {
+    use crate::fetch::{With, Without};
+
+    let mut u = Universe::new();
+    u.create_entity_with((Position { pos: 1 }, Enemy {}));
+    u.create_entity_with((Position { pos: 2 }, Enemy {}, Dead {}));
+    u.create_entity_with((Position { pos: 3 }, Npc {}));
+    u.create_entity_with((Position { pos: 4 },));
+
+    let mut alive: Vec<i32> = u.query::<(&Position, With<Enemy>, Without<Dead>)>().map(|(position, _, _)| position.pos).collect();
+    alive.sort();
+    assert_eq!(vec![1], alive);
+
+    let mut rows: Vec<(i32, bool)> = u.query::<(&Position, Option<&Npc>)>()
+        .map(|(position, npc)| (position.pos, npc.is_some())).collect();
+    rows.sort();
+    assert_eq!(vec![(1, false), (2, false), (3, true), (4, false)], rows);
+
+    u.query::<(Option<&mut Position>, With<Dead>)>().for_each(|(position, _)| position.unwrap().pos = 0);
+    let mut positions: Vec<i32> = u.query::<(&Position,)>().map(|(position,)| position.pos).collect();
+    positions.sort();
+    assert_eq!(vec![0, 1, 3, 4], positions);
+
+    // optional terms of unregistered components are always None
+    struct Unused {}
+    impl Component for Unused {}
+    assert_eq!(4, u.query::<(&Position, Option<&Unused>)>().filter(|(_, unused)| unused.is_none()).count());
+}
+
+#[test]
+#[should_panic(expected = "borrowed mutably and immutably")]
+fn test_typed_query_rejects_optional_aliasing() {
+    let mut u = Universe::new();
+    u.query::<(&Position, Option<&mut Position>)>();
+}
+
+#[test]
+fn test_query_expressions() {
+    use crate::prefab::Prefab;
+    use crate::query::QueryExpr;
+
+    let mut u = Universe::new();
+    u.register_clone_component::<Position>();
+    u.create_entity_with((Position { pos: 0 }, Enemy {}));
+    u.create_entity_with((Position { pos: 1 }, Enemy {}, Dead {}));
+    u.create_entity_with((Position { pos: 2 }, Npc {}));
+    u.create_entity_with((Position { pos: 3 }, Npc {}, Dead {}));
+    u.create_entity_with((Position { pos: 4 },));
+    u.create_entity_with((Enemy {},));
+    u.create_entity_with((Prefab {}, Position { pos: 5 }, Enemy {}));
+
+    let positions = |data: crate::query::EntityData| {
+        let mut positions: Vec<i32> = data.chunks.iter()
+            .flat_map(|chunk| chunk.components::<Position>().unwrap().iter().map(|position| position.pos))
+            .collect();
+        positions.sort();
+        return positions;
+    };
+
+    let targets = QueryExpr::has::<Position>()
+        & QueryExpr::any(vec![TypeId::of::<Enemy>(), TypeId::of::<Npc>()])
+        & !QueryExpr::has::<Dead>();
+    assert_eq!(vec![0, 2], positions(u.get_entities(targets)));
+
+    // live enemies or dead npcs, which a flat all/none/any query cannot express
+    let expr = QueryExpr::has::<Position>()
+        & ((QueryExpr::has::<Enemy>() & !QueryExpr::has::<Dead>()) | (QueryExpr::has::<Npc>() & QueryExpr::has::<Dead>()));
+    assert_eq!(vec![0, 3], positions(u.get_entities(expr)));
+
+    // negation does not pull in prefabs unless asked for
+    let expr = QueryExpr::has::<Position>() & !QueryExpr::any(vec![TypeId::of::<Npc>(), TypeId::of::<Dead>()]);
+    assert_eq!(vec![0, 4], positions(u.get_entities(expr)));
+    let expr = QueryExpr::has::<Position>() & QueryExpr::has::<Prefab>();
+    assert_eq!(vec![5], positions(u.get_entities(expr)));
+
+    // the flat struct is still accepted and converts to a single term
+    let query = EntityQuery { all: vec![TypeId::of::<Position>()], none: vec![TypeId::of::<Dead>()], any: vec![] };
+    assert_eq!(vec![0, 2, 4], positions(u.get_entities(QueryExpr::from(query))));
+}
+
+#[test]
+fn test_cached_query_tracks_new_archetypes() {
+    use crate::query::QueryExpr;
+
+    let mut u = Universe::new();
+    // registered before any of its components exist
+    let query = u.register_query(QueryExpr::has::<Position>() & !QueryExpr::has::<Dead>());
+    assert_eq!(0, u.get_cached_entities(query).num_entities);
+
+    u.create_entity_with((Position { pos: 0 },));
+    u.create_entity_with((Position { pos: 1 }, Dead {}));
+    assert_eq!(1, u.get_cached_entities(query).num_entities);
+    assert_eq!(u.archetype_manager.generation(), u.cached_queries[query.index].generation);
+
+    let cached = u.cached_queries[query.index].archetypes.clone();
+    u.create_entity_with((Position { pos: 2 }, Enemy {}));
+    u.create_entity_with((Npc {},));
+    let data = u.get_cached_entities(query);
+    assert_eq!(2, data.num_entities);
+    assert_eq!(data.archetypes, u.get_entities(QueryExpr::has::<Position>() & !QueryExpr::has::<Dead>()).archetypes);
+    // previously matched archetypes are kept, only the new ones were tested
+    assert_eq!(cached[..], u.cached_queries[query.index].archetypes[..1]);
+
+    // typed access narrows the cached archetypes further
+    u.query_cached::<(&mut Position, &Enemy)>(query).for_each(|(position, _)| position.pos = 10);
+    let mut positions: Vec<i32> = u.query_cached::<(&Position,)>(query).map(|(position,)| position.pos).collect();
+    positions.sort();
+    assert_eq!(vec![0, 10], positions);
+}
+
+#[test]
+fn test_change_detection() {
+    use crate::fetch::{Added, Changed, Without};
+
+    struct Velocity { v: i32 }
+    impl Component for Velocity {}
+
+    let mut u = Universe::new();
+    let moving = u.create_entity_with((Position { pos: 0 }, Velocity { v: 1 }));
+    let idle = u.create_entity_with((Position { pos: 0 },));
+
+    // a system that has never run sees everything
+    let mut last_run = 0;
+    assert_eq!(2, u.query_since::<(&Position, Added<Position>)>(last_run).count());
+    last_run = u.advance_tick();
+    assert_eq!(0, u.query_since::<(&Position, Changed<Position>)>(last_run).count());
+    assert_eq!(0, u.query_since::<(&Position, Added<Position>)>(last_run).count());
+
+    // mutable query access marks the chunk, read-only access does not
+    u.query::<(&Velocity, &mut Position)>().for_each(|(velocity, position)| position.pos += velocity.v);
+    u.query::<(&Position,)>().for_each(|_| {});
+    let changed: Vec<i32> = u.query_since::<(&Position, Changed<Position>)>(last_run).map(|(position, _)| position.pos).collect();
+    assert_eq!(vec![1], changed);
+    assert_eq!(0, u.query_since::<(&Velocity, Changed<Velocity>)>(last_run).count());
+    last_run = u.advance_tick();
+
+    // set_component and get_component_mut
+    u.set_component(idle, Position { pos: 5 });
+    assert_eq!(1, u.query_since::<(Entity, Changed<Position>)>(last_run).filter(|(entity, _)| *entity == idle).count());
+    last_run = u.advance_tick();
+    u.get_component_mut::<Velocity>(moving).unwrap().v = 2;
+    assert_eq!(1, u.query_since::<(&Velocity, Changed<Velocity>)>(last_run).count());
+    assert_eq!(0, u.query_since::<(&Position, Changed<Position>)>(last_run).count());
+    last_run = u.advance_tick();
+
+    // components keep their ticks when the entity moves to another archetype,
+    // only the component that was added counts as added
+    u.add_component_data(idle, Enemy {});
+    assert_eq!(1, u.query_since::<(&Enemy, Added<Enemy>)>(last_run).count());
+    assert_eq!(0, u.query_since::<(&Position, Added<Position>)>(last_run).count());
+    assert_eq!(0, u.query_since::<(&Position, Changed<Position>, Without<Velocity>)>(last_run).count());
+    last_run = u.advance_tick();
+
+    u.set_component(idle, Position { pos: 6 });
+    u.remove_component::<Enemy>(idle);
+    let changed: Vec<Entity> = u.query_since::<(Entity, Changed<Position>)>(last_run).map(|(entity, _)| entity).collect();
+    assert_eq!(vec![idle], changed);
+    last_run = u.advance_tick();
+
+    // bulk spawned entities count as added. ticks are per chunk, so `idle` which shares
+    // the chunk is reported as well
+    u.spawn_batch((0..10).map(|pos| (Position { pos },)));
+    assert_eq!(11, u.query_since::<(&Position, Added<Position>)>(last_run).count());
+}
+
+#[test]
+fn test_query_reports_entities_without_components() {
+    let mut u = Universe::new();
+    let bare: Vec<Entity> = (0..5).map(|_| u.create_entity()).collect();
+    let positioned = u.create_entity_with((Position { pos: 0 },));
+
+    let data = u.get_entities(EntityQuery { all: vec![], none: vec![TypeId::of::<Dead>()], any: vec![] });
+    assert_eq!(6, data.num_entities);
+    let data = u.get_entities(EntityQuery { all: vec![], none: vec![TypeId::of::<Position>()], any: vec![] });
+    assert_eq!(vec![crate::archetype::DEFAULT_ARCHETYPE], data.archetypes);
+    assert_eq!(5, data.num_entities);
+    let mut entities: Vec<Entity> = data.entities().collect();
+    entities.sort_by_key(|entity| entity.id);
+    assert_eq!(bare, entities);
+
+    // entities leave the default archetype when they gain a component and return when they lose it
+    u.add_component_data(bare[0], Position { pos: 1 });
+    u.remove_component::<Position>(positioned);
+    u.destroy_entity(bare[1]);
+    let data = u.get_entities(EntityQuery { all: vec![], none: vec![TypeId::of::<Position>()], any: vec![] });
+    let mut entities: Vec<Entity> = data.entities().collect();
+    entities.sort_by_key(|entity| entity.id);
+    assert_eq!(vec![bare[2], bare[3], bare[4], positioned], entities);
+}
@@ -1,5 +1,7 @@
 use crate::universe::Universe;
 use crate::system::System;
+use crate::component::Component;
+use crate::query::{CachedQuery, QueryExpr};
 use std::cell::Cell;
 
 // test create/get/has systems
@@ -8,9 +10,9 @@ struct TestSystem {
     pub val: i32
 }
 impl System for TestSystem {
-    fn create(&mut self, universe: &mut Universe) {}
-    fn update(&mut self, universe: &mut Universe) {}
-    fn destroy(&mut self, universe: &mut Universe) {}
+    fn create(&mut self, _universe: &mut Universe) {}
+    fn update(&mut self, _universe: &mut Universe) {}
+    fn destroy(&mut self, _universe: &mut Universe) {}
 }
 #[test]
 fn test_systems() {
@@ -27,14 +29,47 @@ struct TestSystem2 {
     pub val: Cell<i32>
 }
 impl System for TestSystem2 {
-    fn create(&mut self, universe: &mut Universe) {}
-    fn update(&mut self, universe: &mut Universe) {}
-    fn destroy(&mut self, universe: &mut Universe) {}
+    fn create(&mut self, _universe: &mut Universe) {}
+    fn update(&mut self, _universe: &mut Universe) {}
+    fn destroy(&mut self, _universe: &mut Universe) {}
 }
 #[test]
 fn test_systems_mutation() {
     let mut u = Universe::new();
-    let mut sys = u.create_system::<TestSystem2>();
+    let sys = u.create_system::<TestSystem2>();
     sys.val.set(69);
     assert_eq!(u.get_system::<TestSystem2>().val.get(), 69);
-}
\ No newline at end of file
+}
+
+// test systems reusing a cached query across updates
+struct Health { hp: i32 }
+impl Component for Health {}
+struct Armor {}
+impl Component for Armor {}
+
+#[derive(Default)]
+struct RegenSystem {
+    query: Option<CachedQuery>
+}
+impl System for RegenSystem {
+    fn create(&mut self, universe: &mut Universe) {
+        self.query = Some(universe.register_query(QueryExpr::has::<Health>()));
+    }
+    fn update(&mut self, universe: &mut Universe) {
+        universe.query_cached::<(&mut Health,)>(self.query.unwrap()).for_each(|(health,)| health.hp += 1);
+    }
+    fn destroy(&mut self, _universe: &mut Universe) {}
+}
+#[test]
+fn test_system_cached_query() {
+    let mut u = Universe::new();
+    let mut sys = RegenSystem::default();
+    sys.create(&mut u);
+
+    let entity = u.create_entity_with((Health { hp: 0 },));
+    sys.update(&mut u);
+    let entity2 = u.create_entity_with((Health { hp: 0 }, Armor {}));
+    sys.update(&mut u);
+    assert_eq!(2, u.get_component_ref::<Health>(entity).unwrap().hp);
+    assert_eq!(1, u.get_component_ref::<Health>(entity2).unwrap().hp);
+}
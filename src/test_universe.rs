@@ -1,10 +1,15 @@
 use crate::universe::Universe;
 use crate::component::Component;
 use crate::entity::Entity;
+use crate::archetype::DEFAULT_ARCHETYPE;
+use std::cell::Cell;
+use std::rc::Rc;
 
+#[derive(Default)]
 struct TestComponent {}
 impl Component for TestComponent {}
 
+#[derive(Clone)]
 struct TestComponent2 { value: i32 }
 impl Component for TestComponent2 {}
 
@@ -49,4 +54,672 @@ fn add_component_get_component() {
     let entity = u.create_entity();
     u.add_component_data(entity, TestComponent2 { value: 1337 });
     assert_eq!(1337, u.get_component::<TestComponent2>(entity).value);
-}
\ No newline at end of file
+}
+#[test]
+#[should_panic]
+fn add_component_twice() {
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component::<TestComponent>(entity);
+    u.add_component::<TestComponent>(entity);
+}
+
+#[test]
+fn add_component_reuses_existing_archetype() {
+    let mut u = Universe::new();
+    let entity1 = u.create_entity();
+    let entity2 = u.create_entity();
+    u.add_component_data(entity1, TestComponent2 { value: 1 });
+    u.add_component_data(entity2, TestComponent2 { value: 2 });
+    assert_eq!(u.archetype_manager.get_archetype_id(entity1), u.archetype_manager.get_archetype_id(entity2));
+    assert_eq!(1, u.get_component::<TestComponent2>(entity1).value);
+    assert_eq!(2, u.get_component::<TestComponent2>(entity2).value);
+}
+
+#[test]
+fn add_component_migrates_existing_data() {
+    #[derive(Clone)]
+    struct TestComponent3 { value: u64 }
+    impl Component for TestComponent3 {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, TestComponent2 { value: 1337 });
+    u.add_component_data(entity, TestComponent3 { value: 42 });
+    assert_eq!(true, u.has_component::<TestComponent2>(entity));
+    assert_eq!(true, u.has_component::<TestComponent3>(entity));
+    assert_eq!(1337, u.get_component::<TestComponent2>(entity).value);
+    assert_eq!(42, u.get_component::<TestComponent3>(entity).value);
+}
+
+#[test]
+fn add_components_in_any_order_share_archetype() {
+    #[derive(Clone)]
+    struct TestComponent3 { value: u64 }
+    impl Component for TestComponent3 {}
+
+    let mut u = Universe::new();
+    let entity1 = u.create_entity();
+    let entity2 = u.create_entity();
+    u.add_component_data(entity1, TestComponent2 { value: 1 });
+    u.add_component_data(entity1, TestComponent3 { value: 2 });
+    u.add_component_data(entity2, TestComponent3 { value: 3 });
+    u.add_component_data(entity2, TestComponent2 { value: 4 });
+    assert_eq!(u.archetype_manager.get_archetype_id(entity1), u.archetype_manager.get_archetype_id(entity2));
+    assert_eq!(1, u.get_component::<TestComponent2>(entity1).value);
+    assert_eq!(2, u.get_component::<TestComponent3>(entity1).value);
+    assert_eq!(4, u.get_component::<TestComponent2>(entity2).value);
+    assert_eq!(3, u.get_component::<TestComponent3>(entity2).value);
+}
+
+#[test]
+fn remove_component_migrates_remaining_data() {
+    #[derive(Clone)]
+    struct TestComponent3 { value: u64 }
+    impl Component for TestComponent3 {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, TestComponent2 { value: 1337 });
+    u.add_component_data(entity, TestComponent3 { value: 42 });
+    u.remove_component::<TestComponent2>(entity);
+    assert_eq!(false, u.has_component::<TestComponent2>(entity));
+    assert_eq!(true, u.has_component::<TestComponent3>(entity));
+    assert_eq!(42, u.get_component::<TestComponent3>(entity).value);
+
+    u.remove_component::<TestComponent3>(entity);
+    assert_eq!(false, u.has_component::<TestComponent3>(entity));
+    assert_eq!(DEFAULT_ARCHETYPE, u.archetype_manager.get_archetype_id(entity));
+}
+
+#[test]
+fn remove_component_drops_value() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, DropCounter { drops: drops.clone() });
+    u.remove_component::<DropCounter>(entity);
+    assert_eq!(1, drops.get());
+}
+
+#[test]
+fn toggle_component_reuses_slots() {
+    #[derive(Default)]
+    struct Stunned {}
+    impl Component for Stunned {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, TestComponent2 { value: 7 });
+    u.add_component::<Stunned>(entity);
+    let stunned_archetype = u.archetype_manager.get_archetype_id(entity);
+    u.remove_component::<Stunned>(entity);
+    let archetype = u.archetype_manager.get_archetype_id(entity);
+    let chunk_ptr = |u: &Universe, archetype: crate::archetype::ArchetypeId| {
+        let chunks = &u.storage[archetype.index].chunks;
+        assert_eq!(1, chunks.len());
+        return chunks[0].component_ptr(0, 0);
+    };
+    let stunned_chunk = chunk_ptr(&u, stunned_archetype);
+    let chunk = chunk_ptr(&u, archetype);
+    for _ in 0..20000 {
+        u.add_component::<Stunned>(entity);
+        u.remove_component::<Stunned>(entity);
+    }
+    assert_eq!(7, u.get_component::<TestComponent2>(entity).value);
+    // both archetypes keep reusing their chunk rather than allocating a new one on every move
+    assert_eq!(stunned_chunk, chunk_ptr(&u, stunned_archetype));
+    assert_eq!(chunk, chunk_ptr(&u, archetype));
+}
+
+#[test]
+#[should_panic]
+fn remove_missing_component() {
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.remove_component::<TestComponent>(entity);
+}
+
+#[test]
+fn component_slices_are_contiguous_per_column() {
+    #[derive(Clone)]
+    struct TestComponent3 { value: u64 }
+    impl Component for TestComponent3 {}
+
+    let mut u = Universe::new();
+    for i in 0..100 {
+        let entity = u.create_entity();
+        u.add_component_data(entity, TestComponent2 { value: i });
+        if i % 2 == 0 {
+            u.add_component_data(entity, TestComponent3 { value: i as u64 });
+        }
+    }
+
+    for slice in u.get_component_slices_mut::<TestComponent2>() {
+        for component in slice.iter_mut() {
+            component.value += 1;
+        }
+    }
+    let slices = u.get_component_slices::<TestComponent2>();
+    assert_eq!(100, slices.iter().map(|s| s.len()).sum::<usize>());
+    assert_eq!((1..=100).sum::<i32>(), slices.iter().flat_map(|s| s.iter()).map(|c| c.value).sum::<i32>());
+    assert_eq!(50, u.get_component_slices::<TestComponent3>().iter().map(|s| s.len()).sum::<usize>());
+    assert_eq!((0..100).step_by(2).sum::<u64>(),
+               u.get_component_slices::<TestComponent3>().iter().flat_map(|s| s.iter()).map(|c| c.value).sum::<u64>());
+}
+
+#[test]
+fn mixed_alignment_components() {
+    #[derive(Clone)]
+    struct Flag { value: u8 }
+    impl Component for Flag {}
+    #[derive(Clone)]
+    #[repr(align(32))]
+    struct Simd { lanes: [f32; 8] }
+    impl Component for Simd {}
+
+    let mut u = Universe::new();
+    for i in 0..10 {
+        let entity = u.create_entity();
+        u.add_component_data(entity, Flag { value: i });
+        u.add_component_data(entity, Simd { lanes: [i as f32; 8] });
+        assert_eq!(i, u.get_component::<Flag>(entity).value);
+        assert_eq!([i as f32; 8], u.get_component::<Simd>(entity).lanes);
+    }
+    for slice in u.get_component_slices::<Simd>() {
+        assert_eq!(slice.as_ptr() as usize % 32, 0);
+    }
+}
+
+#[derive(Clone)]
+struct DropCounter { drops: Rc<Cell<i32>> }
+impl Component for DropCounter {}
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+#[test]
+fn set_component_drops_previous_value() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, DropCounter { drops: drops.clone() });
+    u.set_component(entity, DropCounter { drops: drops.clone() });
+    assert_eq!(1, drops.get());
+}
+
+#[test]
+fn universe_drop_drops_components() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    for _ in 0..3 {
+        let entity = u.create_entity();
+        u.add_component_data(entity, DropCounter { drops: drops.clone() });
+        u.add_component_data(entity, TestComponent2 { value: 1 });
+    }
+    drop(u);
+    assert_eq!(3, drops.get());
+}
+
+#[test]
+fn heap_components_survive_migration() {
+    #[derive(Clone)]
+    struct Name { value: String }
+    impl Component for Name {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, Name { value: String::from("orc") });
+    u.add_component_data(entity, TestComponent2 { value: 1 });
+    u.remove_component::<TestComponent2>(entity);
+    assert_eq!("orc", u.get_component::<Name>(entity).value);
+    u.set_component(entity, Name { value: String::from("goblin") });
+    assert_eq!("goblin", u.get_component::<Name>(entity).value);
+}
+
+#[test]
+fn get_component_ref_and_mut() {
+    struct Health { hp: i32 }
+    impl Component for Health {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    assert!(u.get_component_ref::<Health>(entity).is_none());
+    u.add_component_data(entity, Health { hp: 100 });
+    u.get_component_mut::<Health>(entity).unwrap().hp -= 10;
+    assert_eq!(90, u.get_component_ref::<Health>(entity).unwrap().hp);
+    assert!(u.get_component_ref::<Health>(Entity { id: 0, version: 0 }).is_none());
+    assert!(u.get_component_mut::<TestComponent2>(entity).is_none());
+}
+
+#[test]
+fn destroy_entity_drops_components_and_releases_row() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, DropCounter { drops: drops.clone() });
+    let archetype_id = u.archetype_manager.get_archetype_id(entity);
+    u.destroy_entity(entity);
+
+    assert_eq!(1, drops.get());
+    assert_eq!(false, u.is_valid(entity));
+    assert!(u.archetype_manager.get_archetype_for_entity(entity).is_none());
+    assert!(u.storage[archetype_id.index].chunks.iter().all(|chunk| chunk.is_empty()));
+    assert!(u.get_component_ref::<DropCounter>(entity).is_none());
+    assert!(u.get_component_slices::<DropCounter>().is_empty());
+
+    // recycled id starts out with no components
+    let recycled = u.create_entity();
+    assert_eq!(recycled.id, entity.id);
+    assert_eq!(false, u.has_component::<DropCounter>(recycled));
+    drop(u);
+    assert_eq!(1, drops.get());
+}
+
+#[test]
+fn destroy_entity_keeps_other_rows_intact() {
+    let mut u = Universe::new();
+    let entities: Vec<Entity> = (0..10).map(|i| {
+        let entity = u.create_entity();
+        u.add_component_data(entity, TestComponent2 { value: i });
+        entity
+    }).collect();
+    u.destroy_entity(entities[0]);
+    u.destroy_entity(entities[5]);
+    for (i, entity) in entities.iter().enumerate() {
+        if i == 0 || i == 5 {
+            continue;
+        }
+        assert_eq!(i as i32, u.get_component::<TestComponent2>(*entity).value);
+    }
+}
+
+#[test]
+#[should_panic]
+fn destroy_entity_twice() {
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.destroy_entity(entity);
+    u.destroy_entity(entity);
+}
+
+#[test]
+fn add_remove_populates_archetype_edges() {
+    #[derive(Default)]
+    struct Stunned {}
+    impl Component for Stunned {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, TestComponent2 { value: 1 });
+    let base = u.archetype_manager.get_archetype_id(entity);
+    u.add_component::<Stunned>(entity);
+    let stunned = u.archetype_manager.get_archetype_id(entity);
+
+    let stunned_type = u.get_component_id::<Stunned>().unwrap();
+    assert_eq!(Some(&stunned), u.archetype_manager.get_archetype(base).unwrap().add_edges.get(&stunned_type));
+    assert_eq!(Some(&base), u.archetype_manager.get_archetype(stunned).unwrap().remove_edges.get(&stunned_type));
+
+    let archetype_count = u.archetype_manager.archetypes.len();
+    for _ in 0..100 {
+        u.remove_component::<Stunned>(entity);
+        assert_eq!(base, u.archetype_manager.get_archetype_id(entity));
+        u.add_component::<Stunned>(entity);
+        assert_eq!(stunned, u.archetype_manager.get_archetype_id(entity));
+    }
+    assert_eq!(archetype_count, u.archetype_manager.archetypes.len());
+}
+
+#[test]
+fn add_bundle_single_structural_change() {
+    #[derive(Clone)]
+    struct Velocity { value: f64 }
+    impl Component for Velocity {}
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component_data(entity, TestComponent {});
+    let archetype_count = u.archetype_manager.archetypes.len();
+    u.add_bundle(entity, (TestComponent2 { value: 3 }, Velocity { value: 1.5 }));
+    // only the final archetype is created, no intermediate ones
+    assert_eq!(archetype_count + 1, u.archetype_manager.archetypes.len());
+    assert_eq!(true, u.has_component::<TestComponent>(entity));
+    assert_eq!(3, u.get_component::<TestComponent2>(entity).value);
+    assert_eq!(1.5, u.get_component::<Velocity>(entity).value);
+}
+
+#[test]
+fn create_entity_with_struct_bundle() {
+    #[derive(Clone)]
+    struct Name { value: String }
+    impl Component for Name {}
+    struct Character { name: Name, stats: TestComponent2, drops: DropCounter }
+    crate::impl_bundle!(Character { name: Name, stats: TestComponent2, drops: DropCounter });
+
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let entity = u.create_entity_with(Character {
+        name: Name { value: String::from("orc") },
+        stats: TestComponent2 { value: 12 },
+        drops: DropCounter { drops: drops.clone() }
+    });
+    assert_eq!("orc", u.get_component::<Name>(entity).value);
+    assert_eq!(12, u.get_component::<TestComponent2>(entity).value);
+    assert_eq!(0, drops.get());
+    u.destroy_entity(entity);
+    assert_eq!(1, drops.get());
+}
+
+#[test]
+#[should_panic]
+fn add_bundle_with_existing_component() {
+    let mut u = Universe::new();
+    let entity = u.create_entity_with((TestComponent2 { value: 1 },));
+    u.add_bundle(entity, (TestComponent {}, TestComponent2 { value: 2 }));
+}
+
+#[test]
+fn spawn_batch_places_entities_in_bundle_archetype() {
+    #[derive(Clone)]
+    struct Velocity { value: f64 }
+    impl Component for Velocity {}
+
+    let mut u = Universe::new();
+    let entities = u.spawn_batch((0..20000).map(|i| (TestComponent2 { value: i }, Velocity { value: i as f64 })));
+    assert_eq!(20000, entities.len());
+    let archetype_id = u.archetype_manager.get_archetype_id(entities[0]);
+    for (i, entity) in entities.iter().enumerate() {
+        assert!(u.is_valid(*entity));
+        assert_eq!(archetype_id, u.archetype_manager.get_archetype_id(*entity));
+        assert_eq!(i as i32, u.get_component::<TestComponent2>(*entity).value);
+        assert_eq!(i as f64, u.get_component::<Velocity>(*entity).value);
+    }
+    // chunks are filled before new ones are created
+    let storage = &u.storage[archetype_id.index];
+    assert_eq!(20000usize.div_ceil(storage.chunk_capacity), storage.chunks.len());
+}
+
+#[test]
+fn spawn_batch_reuses_freed_ids() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let first = u.spawn_batch((0..10).map(|_| (DropCounter { drops: drops.clone() },)));
+    for entity in &first {
+        u.destroy_entity(*entity);
+    }
+    assert_eq!(10, drops.get());
+    let second = u.spawn_batch((0..20).map(|_| (DropCounter { drops: drops.clone() },)));
+    assert_eq!(20, second.iter().map(|e| e.id).max().unwrap());
+    assert!(first.iter().all(|e| !u.is_valid(*e)));
+    drop(u);
+    assert_eq!(30, drops.get());
+}
+
+#[test]
+fn spawn_batch_duplicate_components_drops_bundles() {
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    let bundles: Vec<_> = (0..3).map(|_| (DropCounter { drops: drops.clone() }, DropCounter { drops: drops.clone() })).collect();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| u.spawn_batch(bundles)));
+    assert!(result.is_err());
+    assert_eq!(6, drops.get());
+}
+
+#[test]
+fn create_entities_default_initializes_components() {
+    #[derive(Clone, Default)]
+    struct Health { hp: i32 }
+    impl Component for Health {}
+    #[derive(Clone, Default)]
+    struct Name { value: String }
+    impl Component for Name {}
+
+    let mut u = Universe::new();
+    let health = u.register_default_component::<Health>();
+    let name = u.register_default_component::<Name>();
+    let archetype_id = u.find_or_register_archetype(&[health, name]);
+    let mut entities = vec![Entity { id: 0, version: 0 }; 1000];
+    u.create_entities(archetype_id, &mut entities);
+    for entity in &entities {
+        assert!(u.is_valid(*entity));
+        assert_eq!(0, u.get_component::<Health>(*entity).hp);
+        assert_eq!("", u.get_component::<Name>(*entity).value);
+    }
+}
+
+#[test]
+#[should_panic]
+fn create_entities_requires_defaults() {
+    let mut u = Universe::new();
+    let component = u.register_component::<TestComponent2>();
+    let archetype_id = u.find_or_register_archetype(&[component]);
+    let mut entities = vec![Entity { id: 0, version: 0 }; 10];
+    u.create_entities(archetype_id, &mut entities);
+}
+
+#[test]
+fn create_entities_panicking_default_leaves_no_rows() {
+    thread_local! {
+        static DEFAULTS: Cell<i32> = const { Cell::new(0) };
+        static DROPS: Cell<i32> = const { Cell::new(0) };
+    }
+    struct Flaky { _value: Rc<i32> }
+    impl Component for Flaky {}
+    impl Default for Flaky {
+        fn default() -> Self {
+            DEFAULTS.with(|defaults| defaults.set(defaults.get() + 1));
+            if DEFAULTS.with(|defaults| defaults.get()) == 3 {
+                panic!("flaky default");
+            }
+            return Flaky { _value: Rc::new(1) };
+        }
+    }
+    impl Drop for Flaky {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    let mut u = Universe::new();
+    let flaky = u.register_default_component::<Flaky>();
+    let tag = u.register_default_component::<TestComponent>();
+    let archetype_id = u.find_or_register_archetype(&[tag, flaky]);
+    let mut entities = vec![Entity { id: 0, version: 0 }; 10];
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| u.create_entities(archetype_id, &mut entities)));
+    assert!(result.is_err());
+    // the two values built before the panic were dropped, no row or id was handed out
+    assert_eq!(2, DROPS.with(|drops| drops.get()));
+    assert!(u.storage[archetype_id.index].chunks.iter().all(|chunk| chunk.is_empty()));
+    assert_eq!(1, u.create_entity().id);
+    drop(u);
+    assert_eq!(2, DROPS.with(|drops| drops.get()));
+}
+
+#[test]
+fn create_entity_in_declared_archetype() {
+    #[derive(Clone, Default)]
+    struct Health { hp: i32 }
+    impl Component for Health {}
+    #[derive(Clone, Default)]
+    struct Armor { value: u8 }
+    impl Component for Armor {}
+
+    let mut u = Universe::new();
+    let health = u.register_default_component::<Health>();
+    let armor = u.register_default_component::<Armor>();
+    let archetype_id = u.create_archetype(&[armor, health]);
+    assert_eq!(archetype_id, u.create_archetype(&[health, armor]));
+    let archetype_count = u.archetype_manager.archetypes.len();
+
+    let entity = u.create_entity_in(archetype_id);
+    assert_eq!(archetype_id, u.archetype_manager.get_archetype_id(entity));
+    assert_eq!(0, u.get_component::<Health>(entity).hp);
+    assert_eq!(0, u.get_component::<Armor>(entity).value);
+    u.get_component_mut::<Health>(entity).unwrap().hp = 50;
+    assert_eq!(50, u.get_component::<Health>(entity).hp);
+    assert_eq!(archetype_count, u.archetype_manager.archetypes.len());
+
+    let empty = u.create_archetype(&[]);
+    let entity = u.create_entity_in(empty);
+    assert!(u.is_valid(entity));
+    assert_eq!(false, u.has_component::<Health>(entity));
+}
+
+#[test]
+#[should_panic]
+fn create_archetype_with_unregistered_component() {
+    let mut u = Universe::new();
+    u.create_archetype(&[crate::component::ComponentId { index: 3 }]);
+}
+
+#[test]
+fn add_component_writes_default_value() {
+    #[derive(Clone)]
+    struct Name { value: String }
+    impl Component for Name {}
+    impl Default for Name {
+        fn default() -> Self {
+            Name { value: String::from("unnamed") }
+        }
+    }
+
+    let mut u = Universe::new();
+    let entity = u.create_entity();
+    u.add_component::<Name>(entity);
+    assert_eq!("unnamed", u.get_component::<Name>(entity).value);
+    // overwriting drops the default value rather than garbage
+    u.set_component(entity, Name { value: String::from("orc") });
+    assert_eq!("orc", u.get_component::<Name>(entity).value);
+
+    // add_component also registers the default constructor for declared archetypes
+    let archetype_id = u.archetype_manager.get_archetype_id(entity);
+    let entity2 = u.create_entity_in(archetype_id);
+    assert_eq!("unnamed", u.get_component::<Name>(entity2).value);
+}
+
+#[test]
+fn clone_entity_copies_components() {
+    #[derive(Clone)]
+    struct Name { value: String }
+    impl Component for Name {}
+
+    let mut u = Universe::new();
+    u.register_clone_component::<Name>();
+    u.register_clone_component::<TestComponent2>();
+    let entity = u.create_entity_with((Name { value: String::from("orc") }, TestComponent2 { value: 7 }));
+    let copy = u.clone_entity(entity);
+    assert_ne!(entity, copy);
+    assert_eq!(u.archetype_manager.get_archetype_id(entity), u.archetype_manager.get_archetype_id(copy));
+    assert_eq!(7, u.get_component::<TestComponent2>(copy).value);
+
+    // the copy owns its own data
+    u.get_component_mut::<Name>(copy).unwrap().value.push_str(" chief");
+    assert_eq!("orc", u.get_component::<Name>(entity).value);
+    assert_eq!("orc chief", u.get_component::<Name>(copy).value);
+
+    u.destroy_entity(entity);
+    assert_eq!("orc chief", u.get_component::<Name>(copy).value);
+}
+
+#[test]
+fn instantiate_prefab_strips_tag() {
+    use crate::prefab::Prefab;
+
+    let drops = Rc::new(Cell::new(0));
+    let mut u = Universe::new();
+    u.register_clone_component::<TestComponent2>();
+    u.register_clone_component::<DropCounter>();
+    let prefab = u.create_entity_with((Prefab {}, TestComponent2 { value: 3 }, DropCounter { drops: drops.clone() }));
+    let entities = u.instantiate(prefab, 100);
+    assert_eq!(100, entities.len());
+    for entity in &entities {
+        assert_eq!(false, u.has_component::<Prefab>(*entity));
+        assert_eq!(3, u.get_component_ref::<TestComponent2>(*entity).unwrap().value);
+    }
+    assert_eq!(true, u.has_component::<Prefab>(prefab));
+
+    drop(u);
+    assert_eq!(101, drops.get());
+}
+
+#[test]
+#[should_panic]
+fn clone_entity_without_clone_fn() {
+    let mut u = Universe::new();
+    let entity = u.create_entity_with((TestComponent2 { value: 1 },));
+    u.clone_entity(entity);
+}
+
+#[test]
+fn clone_entity_keeps_prefab_archetype() {
+    use crate::prefab::Prefab;
+
+    let mut u = Universe::new();
+    u.register_clone_component::<TestComponent2>();
+    let prefab = u.create_entity_with((Prefab {}, TestComponent2 { value: 3 }));
+    let copy = u.clone_entity(prefab);
+    assert_eq!(true, u.has_component::<Prefab>(copy));
+    assert_eq!(u.archetype_manager.get_archetype_id(prefab), u.archetype_manager.get_archetype_id(copy));
+    assert_eq!(3, u.get_component::<TestComponent2>(copy).value);
+}
+
+#[test]
+fn component_slices_skip_prefabs() {
+    use crate::prefab::Prefab;
+
+    let mut u = Universe::new();
+    u.register_clone_component::<TestComponent2>();
+    let prefab = u.create_entity_with((Prefab {}, TestComponent2 { value: 3 }));
+    u.instantiate(prefab, 2);
+    assert_eq!(vec![2], u.get_component_slices::<TestComponent2>().iter().map(|s| s.len()).collect::<Vec<_>>());
+    assert_eq!(1, u.get_component_slices_mut::<TestComponent2>().len());
+    assert_eq!(1, u.get_component_slices::<Prefab>().len());
+}
+
+#[test]
+fn instantiate_panicking_clone_leaves_no_rows() {
+    thread_local! {
+        static CLONES: Cell<i32> = const { Cell::new(0) };
+        static DROPS: Cell<i32> = const { Cell::new(0) };
+    }
+    struct Flaky { _value: Rc<i32> }
+    impl Component for Flaky {}
+    impl Clone for Flaky {
+        fn clone(&self) -> Self {
+            CLONES.with(|clones| clones.set(clones.get() + 1));
+            if CLONES.with(|clones| clones.get()) == 3 {
+                panic!("flaky clone");
+            }
+            return Flaky { _value: self._value.clone() };
+        }
+    }
+    impl Drop for Flaky {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    let mut u = Universe::new();
+    u.register_clone_component::<Flaky>();
+    u.register_clone_component::<TestComponent2>();
+    let source = u.create_entity_with((TestComponent2 { value: 1 }, Flaky { _value: Rc::new(1) }));
+    let archetype_id = u.archetype_manager.get_archetype_id(source);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| u.instantiate(source, 10)));
+    assert!(result.is_err());
+    // the two clones built before the panic were dropped, only the source is left in storage
+    assert_eq!(2, DROPS.with(|drops| drops.get()));
+    assert_eq!(1, u.storage[archetype_id.index].chunks.iter().map(|chunk| chunk.len()).sum::<usize>());
+    drop(u);
+    assert_eq!(3, DROPS.with(|drops| drops.get()));
+}
+
+#[test]
+#[should_panic(expected = "overflow")]
+fn instantiate_oversized_count_panics() {
+    let mut u = Universe::new();
+    u.register_clone_component::<TestComponent2>();
+    let source = u.create_entity_with((TestComponent2 { value: 1 },));
+    u.instantiate(source, usize::MAX);
+}
@@ -1,44 +1,93 @@
 use std::any::{TypeId, Any};
-use std::collections::{HashMap, LinkedList};
+use std::collections::HashMap;
 use std::mem;
 
-use crate::archetype::{Archetype, ArchetypeManager, ArchetypeStorage, DEFAULT_ARCHETYPE, ArchetypeId};
-use crate::cmd::{CmdChain, CmdCreateEntity};
-use crate::component::Component;
-use crate::entity::Entity;
-use crate::query::{EntityData, EntityQuery};
+use crate::archetype::{Archetype, ArchetypeManager, ArchetypeStorage, DEFAULT_ARCHETYPE, ArchetypeId,
+                       EntityLocation, Signature, StagedRows, INVALID_LOCATION};
+use crate::bundle::{put_bundle, Bundle};
+use crate::cmd::CmdChain;
+use crate::component::{Component, ComponentId, ComponentRegistry};
+use crate::entity::{Entity, EntityAllocator};
+use crate::prefab::Prefab;
+use crate::fetch::{Access, Fetch, Query};
+use crate::query::{CachedQuery, CachedQueryState, ChunkData, EntityData, EntityQuery, ExprMask, QueryExpr};
 use crate::system::System;
 
 /// top level unit of isolation
 pub struct Universe {
-    pub(crate) free_entity_indices: LinkedList<u64>,
-    pub(crate) entity_versions: HashMap<u64, u64>,
+    pub(crate) entities: EntityAllocator,
+    pub(crate) components: ComponentRegistry,
     pub(crate) archetype_manager: ArchetypeManager,
-    pub(crate) storage: HashMap<ArchetypeId, ArchetypeStorage>,
+    /// storage per archetype, indexed by archetype id. entities without components have a row
+    /// in the default archetype's storage, which holds no columns
+    pub(crate) storage: Vec<ArchetypeStorage>,
     pub(crate) systems: HashMap<TypeId, Box<dyn Any>>,
-    pub(crate) singletons: HashMap<TypeId, Box<dyn Any>>
+    pub(crate) singletons: HashMap<TypeId, Box<dyn Any>>,
+    pub(crate) cached_queries: Vec<CachedQueryState>,
+    /// current change tick, stamped on component writes and additions
+    pub(crate) tick: u64
 }
 
 impl Universe {
     pub fn new() -> Universe {
-        let mut free_entity_indices: LinkedList<u64> = LinkedList::new();
-        let mut entity_versions: HashMap<u64, u64> = HashMap::new();
-        for i in 1..10000/*2u64^64-1*/ {
-            free_entity_indices.push_back(i);
-            entity_versions.insert(i, 1);
-        }
+        let mut components = ComponentRegistry::default();
+        components.register_default::<Prefab>();
+        components.register_clone::<Prefab>();
+        let archetype_manager = ArchetypeManager::default();
+        let default_storage = ArchetypeStorage::create(DEFAULT_ARCHETYPE,
+                                                       archetype_manager.get_archetype(DEFAULT_ARCHETYPE).unwrap(),
+                                                       &components);
         Universe {
-            free_entity_indices,
-            entity_versions,
-            archetype_manager: ArchetypeManager::default(),
-            storage: HashMap::new(),
+            entities: EntityAllocator::default(),
+            components,
+            archetype_manager,
+            storage: vec![default_storage],
             systems: HashMap::new(),
-            singletons: HashMap::new()
+            singletons: HashMap::new(),
+            cached_queries: Vec::new(),
+            // 0 is reserved for "never", so everything written is newer than a fresh system's last run
+            tick: 1
         }
     }
 
     pub fn is_valid(&self, entity: Entity) -> bool {
-        return entity.id > 0 && entity.version == *self.entity_versions.get(&entity.id).unwrap_or(&0u64);
+        return self.entities.is_alive(entity);
+    }
+
+    /// current change tick, stamped on components as they are added or written
+    pub fn tick(&self) -> u64 {
+        return self.tick;
+    }
+
+    /// end the current change tick and return it. systems call this at the end of `update` and keep the
+    /// returned tick as their last-run tick for `Changed<T>`/`Added<T>` filters, every later write is newer
+    pub fn advance_tick(&mut self) -> u64 {
+        self.tick += 1;
+        return self.tick - 1;
+    }
+
+    /// dense id of component `T`, registering it on first use
+    pub fn register_component<T: Component + 'static>(&mut self) -> ComponentId {
+        return self.components.register::<T>();
+    }
+
+    /// dense id of component `T` with its default constructor, needed for default initialized entities
+    pub fn register_default_component<T: Component + Default + 'static>(&mut self) -> ComponentId {
+        return self.components.register_default::<T>();
+    }
+
+    /// dense id of component `T` with its clone function, needed to clone and instantiate entities
+    pub fn register_clone_component<T: Component + Clone + 'static>(&mut self) -> ComponentId {
+        return self.components.register_clone::<T>();
+    }
+
+    /// dense id of component `T`, `None` if it has never been registered
+    pub fn get_component_id<T: Component + 'static>(&self) -> Option<ComponentId> {
+        return self.components.get_id::<T>();
+    }
+
+    pub fn get_component_registry(&self) -> &ComponentRegistry {
+        return &self.components;
     }
 
     pub fn create_system<T: System + Any + Default + 'static>(&mut self) -> &mut T {
@@ -82,136 +131,513 @@ impl Universe {
         return cmds.state.last_created_entity.unwrap();
     }
 
+    /// create an entity placed directly into the archetype of the bundle's components
+    pub fn create_entity_with<B: Bundle>(&mut self, bundle: B) -> Entity {
+        let entity = self.create_entity();
+        self.add_bundle(entity, bundle);
+        return entity;
+    }
+
+    /// create one entity per bundle, all placed into the bundle's archetype.
+    /// ids and storage rows are reserved in bulk and component columns are written one after another
+    pub fn spawn_batch<B: Bundle, I: IntoIterator<Item = B>>(&mut self, bundles: I) -> Vec<Entity> {
+        let bundle_components = B::component_ids(&mut self.components);
+        if Signature::new(&bundle_components).components.len() != bundle_components.len() {
+            panic!("ecs: spawn_batch failed: bundle contains duplicate components");
+        }
+        let mut bundles: Vec<B> = bundles.into_iter().collect();
+        let archetype_id = self.find_or_register_archetype(&bundle_components);
+
+        // column and size of each bundle component, resolved once for the whole batch
+        let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
+        let columns: Vec<(usize, usize)> = bundle_components.iter()
+            .map(|c| (archetype.component_index(*c).unwrap(), self.components.get_info(*c).size))
+            .collect();
+        // pointer to every component of every bundle, row major, so each column can be written in one pass
+        let mut ptrs = Vec::with_capacity(bundles.len() * columns.len());
+        for bundle in &bundles {
+            bundle.component_ptrs(&mut |_, src| ptrs.push(src));
+        }
+
+        // no user code runs past this point, the rows are filled as soon as they are handed out
+        let entities = self.entities.alloc_many(bundles.len());
+        let locations = self.storage[archetype_id.index].alloc_entity_indices(&entities);
+        for (entity, location) in entities.iter().zip(&locations) {
+            self.archetype_manager.set_entity_location(*entity, *location);
+        }
+        self.storage[archetype_id.index].mark_added(&locations, self.tick);
+        let storage = &self.storage[archetype_id.index];
+        for (index, (column, size)) in columns.iter().enumerate() {
+            for (row, location) in locations.iter().enumerate() {
+                let dst = compute_ptr_to_component_data(*location, *column, storage);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(ptrs[row * columns.len() + index], dst, *size);
+                }
+            }
+        }
+        // the values were moved into storage bitwise, release the bundles without dropping them
+        unsafe { bundles.set_len(0) };
+        return entities;
+    }
+
+    /// declare an archetype up front so entities can be created directly in it without migrations.
+    /// returns the existing archetype if the component set is already known
+    pub fn create_archetype(&mut self, components: &[ComponentId]) -> ArchetypeId {
+        for component in components {
+            if component.index >= self.components.len() {
+                panic!("ecs: create_archetype failed: unregistered component {:?}", component);
+            }
+        }
+        return self.find_or_register_archetype(components);
+    }
+
+    /// create an entity in an archetype, every component default initialized
+    pub fn create_entity_in(&mut self, archetype_id: ArchetypeId) -> Entity {
+        let mut entities = [Entity { id: 0, version: 0 }];
+        self.create_entities(archetype_id, &mut entities);
+        return entities[0];
+    }
+
+    /// fill `entities` with newly created entities of an archetype, every component default initialized.
+    /// panics if a component of the archetype was registered without a default constructor
+    pub fn create_entities(&mut self, archetype_id: ArchetypeId, entities: &mut [Entity]) {
+        let archetype = match self.archetype_manager.get_archetype(archetype_id) {
+            Some(archetype) => archetype,
+            None => panic!("ecs: create_entities failed: invalid archetype {:?}", archetype_id)
+        };
+        let mut default_fns = Vec::with_capacity(archetype.components.len());
+        for component in &archetype.components {
+            let info = self.components.get_info(*component);
+            match info.default_fn {
+                Some(default_fn) => default_fns.push(default_fn),
+                None => panic!("ecs: create_entities failed: component {} has no default", info.name)
+            }
+        }
+
+        let count = entities.len();
+        let created = self.create_staged(archetype_id, count, |_, staged| {
+            for (column, default_fn) in default_fns.iter().enumerate() {
+                unsafe { staged.fill(column, count, &mut |dst| default_fn(dst)) };
+            }
+        });
+        entities.copy_from_slice(&created);
+    }
+
+    /// create a copy of an entity with clones of all its components, in the same archetype.
+    /// a copy of a prefab is a prefab as well, use `instantiate` to create instances of it
+    pub fn clone_entity(&mut self, entity: Entity) -> Entity {
+        if !self.is_valid(entity) {
+            panic!("ecs: clone_entity failed: invalid entity {}", entity);
+        }
+        let archetype_id = self.archetype_manager.get_archetype_id(entity);
+        return self.clone_into(entity, archetype_id, 1, "clone_entity")[0];
+    }
+
+    /// create `count` copies of an entity with clones of all its components, minus the `Prefab` tag.
+    /// panics if a component was registered without a clone function
+    pub fn instantiate(&mut self, prefab: Entity, count: usize) -> Vec<Entity> {
+        if !self.is_valid(prefab) {
+            panic!("ecs: instantiate failed: invalid entity {}", prefab);
+        }
+        let prefab_component = self.components.get_id::<Prefab>().unwrap();
+        let mut archetype_id = self.archetype_manager.get_archetype_id(prefab);
+        if self.archetype_manager.get_archetype(archetype_id).unwrap().has_component(prefab_component) {
+            archetype_id = self.archetype_without(archetype_id, prefab_component);
+        }
+        return self.clone_into(prefab, archetype_id, count, "instantiate");
+    }
+
+    /// create `count` entities in `archetype_id` with clones of the source entity's components.
+    /// the archetype's components must be a subset of the source's
+    fn clone_into(&mut self, source: Entity, archetype_id: ArchetypeId, count: usize, op: &str) -> Vec<Entity> {
+        let source_location = self.archetype_manager.get_entity_location(source);
+        let source_archetype = self.archetype_manager.get_archetype(source_location.archetype).unwrap();
+        let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
+        let mut clones = Vec::with_capacity(archetype.components.len());
+        for component in &archetype.components {
+            let info = self.components.get_info(*component);
+            match info.clone_fn {
+                Some(clone_fn) => clones.push((source_archetype.component_index(*component).unwrap(), clone_fn)),
+                None => panic!("ecs: {} failed: component {} is not clonable", op, info.name)
+            }
+        }
+
+        return self.create_staged(archetype_id, count, |universe, staged| {
+            let source_storage = &universe.storage[source_location.archetype.index];
+            for (column, (source_column, clone_fn)) in clones.iter().enumerate() {
+                let src = compute_ptr_to_component_data(source_location, *source_column, source_storage);
+                unsafe { staged.fill(column, count, &mut |dst| clone_fn(src, dst)) };
+            }
+        });
+    }
+
+    /// create `count` entities in `archetype_id`, `build` fills every column of their staged rows.
+    /// the values are built before any id or row is handed out, so a panicking user `Default` or
+    /// `Clone` impl leaves nothing behind
+    fn create_staged<F: FnOnce(&Universe, &mut StagedRows)>(&mut self, archetype_id: ArchetypeId, count: usize,
+                                                            build: F) -> Vec<Entity> {
+        let mut staged = StagedRows::create(&self.storage[archetype_id.index], count);
+        build(self, &mut staged);
+
+        let entities = self.entities.alloc_many(count);
+        let locations = self.storage[archetype_id.index].alloc_staged(&entities, staged);
+        for (entity, location) in entities.iter().zip(&locations) {
+            self.archetype_manager.set_entity_location(*entity, *location);
+        }
+        self.storage[archetype_id.index].mark_added(&locations, self.tick);
+        return entities;
+    }
+
     pub fn destroy_entity(&mut self, entity: Entity) {
         let mut cmds = CmdChain::new();
         cmds.destroy_entity(entity);
         self.exec(&mut cmds);
     }
 
-    pub fn add_component<T: Component + 'static>(&mut self, entity: Entity) {
+    /// add a default initialized component, storage rows never hold uninitialized values
+    pub fn add_component<T: Component + Default + 'static>(&mut self, entity: Entity) {
+        self.components.register_default::<T>();
+        self.add_component_data(entity, T::default());
+    }
+
+    /// add every component of the bundle with a single move to the final archetype
+    pub fn add_bundle<B: Bundle>(&mut self, entity: Entity, bundle: B) {
         if !self.is_valid(entity) {
-            panic!("ecs: add_component failed: invalid entity {}", entity);
+            panic!("ecs: add_bundle failed: invalid entity {}", entity);
+        }
+        let bundle_components = B::component_ids(&mut self.components);
+        let entity_archetype_id = self.archetype_manager.get_archetype_id(entity);
+        let entity_archetype = self.archetype_manager.get_archetype(entity_archetype_id).unwrap();
+        let mut components = entity_archetype.components.clone();
+        for component in &bundle_components {
+            if components.contains(component) {
+                panic!("ecs: add_bundle failed: component {} already exists on entity {}",
+                       self.components.get_info(*component).name, entity);
+            }
+            components.push(*component);
         }
-        let component_type_id = TypeId::of::<T>();
-        let entity_archetype = self.archetype_manager.get_archetype_id(entity);
-        if entity_archetype == DEFAULT_ARCHETYPE {
-            let existing_archetype = self.archetype_manager.find_archetype(vec![component_type_id]);
-            if existing_archetype.is_some() {
-
-            } else {
-                // fresh new archetype
-                let archetype = Archetype {
-                    component_types: vec![component_type_id],
-                    component_sizes: vec![mem::size_of::<T>()]
-                };
-                // create storage
-                let archetype_id = ArchetypeId { index: self.archetype_manager.archetype_index_seq };
-                let archetype_size = archetype.component_sizes.iter().sum();
-                let mut archetype_storage = ArchetypeStorage::create(archetype_id, archetype_size);
-                // store entity
-                self.archetype_manager.set_entity_archetype(entity, archetype_id);
-                archetype_storage.alloc_entity_index(entity);
-                self.storage.insert(archetype_id, archetype_storage);
-                // update archetype manager
-                self.archetype_manager.archetypes.push(archetype);
-                self.archetype_manager.archetype_index_seq += 1;
-            }
-        }
-       /* if entity_archetype.is_some() {
-            let archetype = self.archetype_manager.get_archetype(entity_archetype).unwrap();
-            if archetype.component_types.contains(&component_type_id) {
-                panic!("ecs: component already exists on entity {}", entity);
-            }
-        } else {
-
-        }*/
+
+        let target_archetype_id = self.find_or_register_archetype(&components);
+        self.move_entity(entity, entity_archetype_id, target_archetype_id);
+
+        let location = self.archetype_manager.get_entity_location(entity);
+        let archetype = self.archetype_manager.get_archetype(target_archetype_id).unwrap();
+        let storage = &self.storage[target_archetype_id.index];
+        let registry = &self.components;
+        put_bundle(bundle, &mut |index, src| {
+            let component = bundle_components[index];
+            let column = archetype.component_index(component).unwrap();
+            let dst = compute_ptr_to_component_data(location, column, storage);
+            unsafe {
+                std::ptr::copy_nonoverlapping(src, dst, registry.get_info(component).size);
+            }
+        });
+    }
+
+    pub fn remove_component<T: Component + 'static>(&mut self, entity: Entity) {
+        // move the value out of storage before its slot is released
+        let data_ptr = self.compute_component_ptr::<T>(entity, "remove_component");
+        let component: T = unsafe { std::ptr::read::<T>(data_ptr) };
+
+        let component_id = self.components.get_id::<T>().unwrap();
+        let entity_archetype_id = self.archetype_manager.get_archetype_id(entity);
+        let target_archetype_id = self.archetype_without(entity_archetype_id, component_id);
+        self.move_entity(entity, entity_archetype_id, target_archetype_id);
+        mem::drop(component);
     }
 
     pub fn add_component_data<T: Component + 'static>(&mut self, entity: Entity, component: T) {
-        self.add_component::<T>(entity);
-        self.set_component::<T>(entity, component);
+        if !self.is_valid(entity) {
+            panic!("ecs: add_component failed: invalid entity {}", entity);
+        }
+        let component_id = self.components.register::<T>();
+        let entity_archetype_id = self.archetype_manager.get_archetype_id(entity);
+        let entity_archetype = self.archetype_manager.get_archetype(entity_archetype_id).unwrap();
+        if entity_archetype.has_component(component_id) {
+            panic!("ecs: add_component failed: component already exists on entity {}", entity);
+        }
+
+        let target_archetype_id = self.archetype_with(entity_archetype_id, component_id);
+        self.move_entity(entity, entity_archetype_id, target_archetype_id);
+        let data_ptr = self.compute_component_ptr::<T>(entity, "add_component_data");
+        unsafe {
+            std::ptr::write::<T>(data_ptr, component);
+        }
     }
 
     pub fn has_component<T: Component + 'static>(&mut self, entity: Entity) -> bool {
         if !self.is_valid(entity) {
             panic!("ecs: has_component failed: invalid entity {}", entity);
         }
-        let component_type_id = TypeId::of::<T>();
+        let component = match self.components.get_id::<T>() {
+            Some(component) => component,
+            None => return false
+        };
         let entity_archetype_id = self.archetype_manager.get_archetype_id(entity);
-        if entity_archetype_id == DEFAULT_ARCHETYPE {
-            return false;
-        }
-        return self.archetype_manager.get_archetype(entity_archetype_id).unwrap()
-            .component_types.contains(&component_type_id);
+        return self.archetype_manager.get_archetype(entity_archetype_id).unwrap().has_component(component);
     }
 
+    /// overwrite a component, dropping the previous value
     pub fn set_component<T: Component + 'static>(&mut self, entity: Entity, component: T) {
-        if !self.is_valid(entity) {
-            panic!("ecs: set_component failed: invalid entity {}", entity);
+        let data_ptr = self.compute_component_ptr::<T>(entity, "set_component");
+        unsafe {
+            *data_ptr = component;
         }
-        let archetype_id = self.archetype_manager.get_archetype_id(entity);
-        let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
-        let component_type_id = TypeId::of::<T>();
-        if !archetype.component_types.contains(&component_type_id) {
-            panic!("ecs: set_component failed: entity has no such component");
+        self.mark_changed::<T>(entity);
+    }
+
+    /// copy of a component. storage keeps ownership of the original value
+    pub fn get_component<T: Component + Clone + 'static>(&mut self, entity: Entity) -> T {
+        let data_ptr = self.compute_component_ptr::<T>(entity, "get_component");
+        return unsafe { (*data_ptr).clone() };
+    }
+
+    /// borrow a component, `None` if the entity is invalid or has no such component
+    pub fn get_component_ref<T: Component + 'static>(&self, entity: Entity) -> Option<&T> {
+        return self.find_component_ptr::<T>(entity).map(|data_ptr| unsafe { &*data_ptr });
+    }
+
+    /// mutably borrow a component, `None` if the entity is invalid or has no such component
+    /// counts as a write for change detection
+    pub fn get_component_mut<T: Component + 'static>(&mut self, entity: Entity) -> Option<&mut T> {
+        let data_ptr = self.find_component_ptr::<T>(entity)?;
+        self.mark_changed::<T>(entity);
+        return Some(unsafe { &mut *data_ptr });
+    }
+
+    /// contiguous columns of component `T`, one slice per chunk of every archetype containing `T`.
+    /// prefabs are skipped
+    pub fn get_component_slices<T: Component + 'static>(&self) -> Vec<&[T]> {
+        let mut slices = Vec::new();
+        let component = match self.components.get_id::<T>() {
+            Some(component) => component,
+            None => return slices
+        };
+        // prefabs are templates, not live entities. like queries, only slices of `Prefab` itself include them
+        let prefab = self.components.get_id::<Prefab>().unwrap();
+        for storage in &self.storage {
+            let archetype = self.archetype_manager.get_archetype(storage.archetype_id).unwrap();
+            if component != prefab && archetype.has_component(prefab) {
+                continue;
+            }
+            if let Some(column) = archetype.component_index(component) {
+                for chunk in storage.chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                    slices.push(unsafe { chunk.column::<T>(column) });
+                }
+            }
         }
-        let component_type_index = archetype.component_types.iter()
-            .position(|c| *c == component_type_id).unwrap();
-        let mut archetype_storage: &mut ArchetypeStorage = self.storage.get_mut(&archetype_id).unwrap();
+        return slices;
+    }
 
-        let data_ptr: *mut T = compute_ptr_to_component_data(entity, component_type_index,
-                                                                   archetype, &mut archetype_storage) as *mut T;
-        unsafe {
-            std::ptr::write::<T>(data_ptr, component);
+    /// mutable contiguous columns of component `T`, one slice per chunk of every archetype containing `T`.
+    /// prefabs are skipped. counts as a write to every returned chunk for change detection
+    pub fn get_component_slices_mut<T: Component + 'static>(&mut self) -> Vec<&mut [T]> {
+        let mut slices = Vec::new();
+        let component = match self.components.get_id::<T>() {
+            Some(component) => component,
+            None => return slices
+        };
+        let prefab = self.components.get_id::<Prefab>().unwrap();
+        for storage in &mut self.storage {
+            let archetype = self.archetype_manager.get_archetype(storage.archetype_id).unwrap();
+            if component != prefab && archetype.has_component(prefab) {
+                continue;
+            }
+            if let Some(column) = archetype.component_index(component) {
+                for chunk in storage.chunks.iter_mut().filter(|chunk| !chunk.is_empty()) {
+                    chunk.mark_changed(column, self.tick);
+                    slices.push(unsafe { chunk.column_mut::<T>(column) });
+                }
+            }
         }
+        return slices;
     }
 
-    pub fn get_component<T: Component + 'static>(&mut self, entity: Entity) -> T {
-        if !self.is_valid(entity) {
-            panic!("ecs: get_component failed: invalid entity {}", entity);
+    /// register a new archetype, returns the unique archetype id
+    pub(crate) fn register_archetype(&mut self, archetype: Archetype) -> ArchetypeId {
+        // create storage
+        let archetype_id = ArchetypeId { index: self.archetype_manager.archetype_index_seq };
+        self.storage.push(ArchetypeStorage::create(archetype_id, &archetype, &self.components));
+        return self.archetype_manager.register_archetype(archetype);
+    }
+
+    /// archetype reached by adding `component` to an archetype. resolved through the archetype's cached
+    /// add edge, falling back to a lookup (or registration) which then populates the edge both ways
+    pub(crate) fn archetype_with(&mut self, archetype_id: ArchetypeId, component: ComponentId) -> ArchetypeId {
+        let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
+        if let Some(target) = archetype.add_edges.get(&component) {
+            return *target;
         }
-        let archetype_id = (&mut self.archetype_manager).get_archetype_id(entity);
-        let archetype = (&mut self.archetype_manager).get_archetype(archetype_id).unwrap();
-        let component_type_id = TypeId::of::<T>();
-        if !archetype.component_types.contains(&component_type_id) {
-            panic!("ecs: get_component failed: entity has no such component");
+
+        // target archetype is the current component set plus the new component
+        let mut components = archetype.components.clone();
+        components.push(component);
+        let target = self.find_or_register_archetype(&components);
+        self.archetype_manager.set_edge(archetype_id, component, target);
+        return target;
+    }
+
+    /// archetype reached by removing `component` from an archetype, see `archetype_with`
+    pub(crate) fn archetype_without(&mut self, archetype_id: ArchetypeId, component: ComponentId) -> ArchetypeId {
+        let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
+        if let Some(target) = archetype.remove_edges.get(&component) {
+            return *target;
         }
-        let component_type_index = archetype.component_types.iter()
-            .position(|c| *c == component_type_id).unwrap();
-        let mut archetype_storage: &mut ArchetypeStorage = self.storage.get_mut(&archetype_id).unwrap();
 
-        let data_ptr: *const u8 = compute_ptr_to_component_data(entity, component_type_index,
-                                                          archetype, &mut archetype_storage) as *const u8;
-        let component: T = unsafe { std::ptr::read::<T>(data_ptr as *const _) };
-        return component;
+        // target archetype is the current component set minus the removed component
+        let components: Vec<ComponentId> = archetype.components.iter()
+            .filter(|c| **c != component).copied().collect();
+        let target = self.find_or_register_archetype(&components);
+        self.archetype_manager.set_edge(target, component, archetype_id);
+        return target;
     }
 
-    // register a new archetype, returns the unique archetype id
-    /*pub(crate) fn register_archetype(&mut self, archetype: Archetype) -> ArchetypeId {
-        // create storage
-        let archetype_id = ArchetypeId { index: self.archetype_manager.archetype_index_seq };
-        let archetype_size = (&archetype).component_sizes.iter().sum();
-        self.storage.insert(archetype_id, ArchetypeStorage::create(archetype_id, archetype_size));
-        // store & increment index counter
-        self.archetype_manager.archetypes.push(archetype);
-        self.archetype_manager.archetype_index_seq += 1;
-        return archetype_id;
-    }*/
+    pub(crate) fn find_or_register_archetype(&mut self, components: &[ComponentId]) -> ArchetypeId {
+        return match self.archetype_manager.find_archetype(components) {
+            Some(archetype_id) => archetype_id,
+            None => self.register_archetype(Archetype::new(components))
+        };
+    }
+
+    /// drop an entity's components and release its storage row, leaving it without a location
+    pub(crate) fn release_entity_storage(&mut self, entity: Entity) {
+        let location = self.archetype_manager.get_entity_location(entity);
+        self.storage[location.archetype.index].drop_entity(location, &mut self.archetype_manager.entity_locations);
+        self.archetype_manager.set_entity_location(entity, INVALID_LOCATION);
+    }
 
-    pub fn get_entities(&self, query: EntityQuery) -> EntityData {
-        let mut results = EntityData { num_entities: 0 };
-        'outer: for i in 0..self.archetype_manager.archetypes.len() {
-            let archetype = &self.archetype_manager.archetypes[i];
-            for required_component_type in &query.all {
-                if !archetype.component_types.contains(&required_component_type) {
-                    // skip archetype
-                    continue 'outer;
+    /// give newly allocated entities a row in the default archetype
+    pub(crate) fn place_in_default_archetype(&mut self, entities: &[Entity]) {
+        let locations = self.storage[DEFAULT_ARCHETYPE.index].alloc_entity_indices(entities);
+        for (entity, location) in entities.iter().zip(&locations) {
+            self.archetype_manager.set_entity_location(*entity, *location);
+        }
+    }
+
+    /// structural move of an entity between archetypes.
+    /// allocates a slot in the target storage, copies every component shared by both archetypes
+    /// and frees the slot in the source storage. components not present in the target are not
+    /// copied, components not present in the source are left for the caller to write. those rows
+    /// hold no values yet, so the caller writes them without dropping anything, and nothing may
+    /// panic before they are written or storage would be left with uninitialized rows
+    pub(crate) fn move_entity(&mut self, entity: Entity, from: ArchetypeId, to: ArchetypeId) {
+        if from == to {
+            return;
+        }
+        let from_location = self.archetype_manager.get_entity_location(entity);
+        let to_location = self.storage[to.index].alloc_entity_index(entity);
+        let from_archetype = self.archetype_manager.get_archetype(from).unwrap();
+        let to_archetype = self.archetype_manager.get_archetype(to).unwrap();
+        // components new to the entity count as added, moved ones carry their ticks over
+        let to_chunk = &self.storage[to.index].chunks[to_location.chunk];
+        for (to_index, component) in to_archetype.components.iter().enumerate() {
+            match from_archetype.component_index(*component) {
+                Some(from_index) => {
+                    let from_chunk = &self.storage[from.index].chunks[from_location.chunk];
+                    to_chunk.mark_added(to_index, from_chunk.added_ticks[from_index].get());
+                    to_chunk.mark_changed(to_index, from_chunk.changed_ticks[from_index].get());
                 }
+                None => to_chunk.mark_added(to_index, self.tick)
+            }
+        }
+        for (from_index, component) in from_archetype.components.iter().enumerate() {
+            let to_index = match to_archetype.component_index(*component) {
+                Some(index) => index,
+                None => continue
+            };
+            let size = self.components.get_info(*component).size;
+            let src = compute_ptr_to_component_data(from_location, from_index, &self.storage[from.index]);
+            let dst = compute_ptr_to_component_data(to_location, to_index, &self.storage[to.index]);
+            unsafe {
+                std::ptr::copy_nonoverlapping(src as *const u8, dst, size);
+            }
+        }
+        self.storage[from.index].free_entity_index(from_location, &mut self.archetype_manager.entity_locations);
+        self.archetype_manager.set_entity_location(entity, to_location);
+    }
+
+    /// matching archetypes and their chunks with entity lists and component columns
+    pub fn get_entities<Q: Into<QueryExpr>>(&self, query: Q) -> EntityData<'_> {
+        let mask = query.into().to_mask(&self.components);
+        return self.entity_data(self.get_matching_archetypes(&mask));
+    }
+
+    /// register a query whose matching archetypes are cached, e.g. once in `System::create`
+    pub fn register_query<Q: Into<QueryExpr>>(&mut self, query: Q) -> CachedQuery {
+        self.cached_queries.push(CachedQueryState::new(query.into(), &self.components));
+        return CachedQuery { index: self.cached_queries.len() - 1 };
+    }
+
+    /// like `get_entities`, only testing archetypes created since the query was last used
+    pub fn get_cached_entities(&mut self, query: CachedQuery) -> EntityData<'_> {
+        let archetypes = self.update_cached_query(query).to_vec();
+        return self.entity_data(archetypes);
+    }
+
+    /// typed query restricted to the archetypes of a cached query
+    pub fn query_cached<'a, Q: Fetch<'a>>(&'a mut self, cached: CachedQuery) -> Query<'a, Q> {
+        return self.query_cached_since(cached, 0);
+    }
+
+    /// `query_cached` with `Changed<T>`/`Added<T>` terms compared against a system's last-run tick
+    pub fn query_cached_since<'a, Q: Fetch<'a>>(&'a mut self, cached: CachedQuery, last_run: u64) -> Query<'a, Q> {
+        let mut query = EntityQuery { all: vec![], none: vec![], any: vec![] };
+        Q::add_terms(&mut query, &mut Access::default());
+        // the fetch terms still have to match, the cached query may be broader than `Q`
+        let mask = QueryExpr::from(query).to_mask(&self.components);
+        let mut archetypes = self.update_cached_query(cached).to_vec();
+        archetypes.retain(|archetype_id| mask.matches(&self.archetype_manager.archetypes[archetype_id.index].component_set));
+        return Query::new(self.entity_data(archetypes).chunks, last_run);
+    }
+
+    /// bring a cached query up to date with the archetype generation, returns its archetypes
+    fn update_cached_query(&mut self, query: CachedQuery) -> &[ArchetypeId] {
+        let state = &mut self.cached_queries[query.index];
+        if state.generation != self.archetype_manager.generation() {
+            state.update(&self.archetype_manager.archetypes, &self.components);
+        }
+        return &state.archetypes;
+    }
+
+    fn entity_data(&self, archetypes: Vec<ArchetypeId>) -> EntityData<'_> {
+        let mut results = EntityData { num_entities: 0, archetypes: Vec::with_capacity(archetypes.len()), chunks: Vec::new() };
+        for archetype_id in archetypes {
+            let archetype = self.archetype_manager.get_archetype(archetype_id).unwrap();
+            for chunk in self.storage[archetype_id.index].chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                results.num_entities += chunk.len();
+                results.chunks.push(ChunkData::new(archetype_id, archetype, &self.components, chunk, self.tick));
             }
+            results.archetypes.push(archetype_id);
         }
         return results;
     }
 
+    /// typed query, e.g. `u.query::<(Entity, &Position, &mut Velocity)>().for_each(|(e, p, v)| ...)`.
+    /// panics if a component is borrowed mutably more than once, or both mutably and immutably
+    pub fn query<'a, Q: Fetch<'a>>(&'a mut self) -> Query<'a, Q> {
+        return self.query_since(0);
+    }
+
+    /// typed query whose `Changed<T>`/`Added<T>` terms only match chunks written or added to after
+    /// `last_run`, e.g. `u.query_since::<(&Position, Changed<Position>)>(self.last_run)`.
+    /// change ticks are tracked per chunk, so unchanged entities sharing a chunk with changed ones match as well
+    pub fn query_since<'a, Q: Fetch<'a>>(&'a mut self, last_run: u64) -> Query<'a, Q> {
+        let mut query = EntityQuery { all: vec![], none: vec![], any: vec![] };
+        Q::add_terms(&mut query, &mut Access::default());
+        return Query::new(self.get_entities(query).chunks, last_run);
+    }
+
+    /// archetypes whose component set satisfies the query mask
+    pub fn get_matching_archetypes(&self, mask: &ExprMask) -> Vec<ArchetypeId> {
+        let mut archetypes = Vec::new();
+        for (index, archetype) in self.archetype_manager.archetypes.iter().enumerate() {
+            if mask.matches(&archetype.component_set) {
+                archetypes.push(ArchetypeId { index });
+            }
+        }
+        return archetypes;
+    }
+
     pub fn set_singleton<T: Component + Any + 'static>(&mut self, component: T) -> &T {
         let type_id = TypeId::of::<T>();
         if self.singletons.contains_key(&type_id) {
@@ -231,20 +657,40 @@ impl Universe {
         return self.singletons.contains_key(&TypeId::of::<T>());
     }
 
+    /// pointer to an entity's component `T`, `None` if the entity is invalid or has no such component
+    fn find_component_ptr<T: Component + 'static>(&self, entity: Entity) -> Option<*mut T> {
+        if !self.is_valid(entity) {
+            return None;
+        }
+        let location = self.archetype_manager.get_entity_location(entity);
+        let archetype = self.archetype_manager.get_archetype(location.archetype).unwrap();
+        let component_type_index = archetype.component_index(self.components.get_id::<T>()?)?;
+        let archetype_storage: &ArchetypeStorage = &self.storage[location.archetype.index];
+        return Some(compute_ptr_to_component_data(location, component_type_index, archetype_storage) as *mut T);
+    }
+
+    /// record a write to an entity's component `T` at the current tick
+    fn mark_changed<T: Component + 'static>(&self, entity: Entity) {
+        let location = self.archetype_manager.get_entity_location(entity);
+        let archetype = self.archetype_manager.get_archetype(location.archetype).unwrap();
+        if let Some(column) = archetype.component_index(self.components.get_id::<T>().unwrap()) {
+            self.storage[location.archetype.index].chunks[location.chunk].mark_changed(column, self.tick);
+        }
+    }
+
+    /// validated pointer to an entity's component `T`, panics naming `op` on failure
+    fn compute_component_ptr<T: Component + 'static>(&mut self, entity: Entity, op: &str) -> *mut T {
+        if !self.is_valid(entity) {
+            panic!("ecs: {} failed: invalid entity {}", op, entity);
+        }
+        return match self.find_component_ptr::<T>(entity) {
+            Some(data_ptr) => data_ptr,
+            None => panic!("ecs: {} failed: entity has no such component", op)
+        };
+    }
 }
 
-fn compute_ptr_to_component_data(entity: Entity, component_type_index: usize,
-                                     archetype: &Archetype, storage: &mut ArchetypeStorage) -> *mut u8 {
-    let archetype_total_size: usize = archetype.component_sizes.iter().sum();
-    // compute base pointer into archetype storage data
-    let entity_data_index: usize = storage.entity_indices[&entity];
-    // compute offset into archetype component data
-    let mut component_data_offset: usize = 0;
-    for i in 0..component_type_index as usize {
-        component_data_offset += archetype.component_sizes[i]
-    }
-    let data_offset: isize = ((entity_data_index * archetype_total_size) + component_data_offset) as isize;
-    unsafe {
-        return storage.data.as_mut_ptr().offset(data_offset) as *mut u8;
-    }
-}
\ No newline at end of file
+fn compute_ptr_to_component_data(location: EntityLocation, component_type_index: usize,
+                                     storage: &ArchetypeStorage) -> *mut u8 {
+    return storage.chunks[location.chunk].component_ptr(component_type_index, location.row);
+}